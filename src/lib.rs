@@ -16,10 +16,13 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
-use std::io::Read;
-use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
-use std::{fmt, io, process};
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::string::FromUtf8Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fmt, fs, io, mem, process, thread};
 
 /// Type of error.
 #[derive(Debug)]
@@ -28,18 +31,75 @@ pub enum ErrorKind {
     /// example, this variant is used if the program does not exist.
     Run(io::Error),
 
-    /// The command exited non-zero or due to a signal.
+    /// The command exited non-zero. On platforms other than Unix, this
+    /// is also used if the command exited due to a signal.
     Exit(process::ExitStatus),
+
+    /// The command was terminated by a signal. Contains the signal
+    /// number. This is only used on Unix.
+    Signal(i32),
+
+    /// The command did not finish before the timeout elapsed and was
+    /// killed. Contains the time elapsed since the command started.
+    Timeout(Duration),
+
+    /// The command's captured output had more lines than
+    /// `Command::max_output_lines` allows, and the command was killed
+    /// if it was still running. Contains the line limit.
+    OutputLimit(usize),
+
+    /// The output of the command could not be parsed. This is used by
+    /// [`Command::run_json`].
+    #[cfg(feature = "serde_json")]
+    Parse(serde_json::Error),
+}
+
+/// Neither `io::Error` nor `serde_json::Error` implement `Clone`, so
+/// the clone is rebuilt from the error's kind and message. The
+/// original error's source is not preserved.
+impl Clone for ErrorKind {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Run(err) => Self::Run(match err.raw_os_error() {
+                Some(code) => io::Error::from_raw_os_error(code),
+                None => io::Error::new(err.kind(), err.to_string()),
+            }),
+            Self::Exit(status) => Self::Exit(*status),
+            Self::Signal(signal) => Self::Signal(*signal),
+            Self::Timeout(elapsed) => Self::Timeout(*elapsed),
+            Self::OutputLimit(limit) => Self::OutputLimit(*limit),
+            #[cfg(feature = "serde_json")]
+            Self::Parse(err) => Self::Parse(
+                <serde_json::Error as serde::de::Error>::custom(err),
+            ),
+        }
+    }
 }
 
 /// Error returned by [`Command::run`].
-#[derive(Debug)]
+///
+/// Cloning an error with kind `Run` or `Parse` does not preserve the
+/// source of the underlying error; see the `Clone` impl of
+/// [`ErrorKind`].
+#[derive(Clone, Debug)]
 pub struct Error {
     /// The command that caused the error.
     pub command: Command,
 
     /// The type of error.
     pub kind: ErrorKind,
+
+    /// Output produced before the error occurred. This is set if the
+    /// command's output is captured and the command failed, if the
+    /// command timed out or exceeded the output limit while running
+    /// with [`Command::run`], or if the output could not be parsed.
+    pub output: Option<Output>,
+
+    /// Where the program was found in the `PATH` when the error was
+    /// created. This is only set if the error kind is `Run`, the
+    /// program was found, and the path differs from the command's
+    /// `program`. It is not set if the command is run `via_shell`.
+    pub resolved_program: Option<PathBuf>,
 }
 
 impl Error {
@@ -52,6 +112,45 @@ impl Error {
     pub fn is_exit_error(&self) -> bool {
         matches!(self.kind, ErrorKind::Exit(_))
     }
+
+    /// Check if the error kind is `Signal`.
+    pub fn is_signal_error(&self) -> bool {
+        matches!(self.kind, ErrorKind::Signal(_))
+    }
+
+    /// Check if the error kind is `Timeout`.
+    pub fn is_timeout_error(&self) -> bool {
+        matches!(self.kind, ErrorKind::Timeout(_))
+    }
+
+    /// Check if the error kind is `OutputLimit`.
+    pub fn is_output_limit_error(&self) -> bool {
+        matches!(self.kind, ErrorKind::OutputLimit(_))
+    }
+
+    /// Check if the error kind is `Parse`.
+    #[cfg(feature = "serde_json")]
+    pub fn is_parse_error(&self) -> bool {
+        matches!(self.kind, ErrorKind::Parse(_))
+    }
+
+    /// Check if the error kind is `Run` and the program was not
+    /// found.
+    pub fn is_not_found(&self) -> bool {
+        match &self.kind {
+            ErrorKind::Run(err) => err.kind() == io::ErrorKind::NotFound,
+            _ => false,
+        }
+    }
+
+    /// Get the exit code if the error kind is `Exit`, otherwise
+    /// `None`.
+    pub fn exit_code(&self) -> Option<i32> {
+        match &self.kind {
+            ErrorKind::Exit(status) => status.code(),
+            _ => None,
+        }
+    }
 }
 
 /// Internal trait for converting an io::Error to an Error.
@@ -64,6 +163,13 @@ impl<T> IntoError<T> for Result<T, io::Error> {
         self.map_err(|err| Error {
             command: command.clone(),
             kind: ErrorKind::Run(err),
+            output: None,
+            resolved_program: command
+                .via_shell
+                .is_none()
+                .then(|| command.resolve_program())
+                .flatten()
+                .filter(|path| *path != command.program),
         })
     }
 }
@@ -71,15 +177,52 @@ impl<T> IntoError<T> for Result<T, io::Error> {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match &self.kind {
-            ErrorKind::Run(err) => write!(
+            ErrorKind::Run(err) => {
+                write!(
+                    f,
+                    "failed to run '{}'",
+                    self.command.command_line_lossy()
+                )?;
+                // Show where the program was found in the `PATH` to
+                // make it clear which program failed.
+                if let Some(path) = &self.resolved_program {
+                    write!(f, " ({})", path.display())?;
+                }
+                write!(f, ": {}", err)
+            }
+            ErrorKind::Exit(err) => write!(
                 f,
-                "failed to run '{}': {}",
+                "command '{}' failed: {}",
                 self.command.command_line_lossy(),
                 err
             ),
-            ErrorKind::Exit(err) => write!(
+            ErrorKind::Signal(signal) => {
+                write!(
+                    f,
+                    "command '{}' terminated by signal ",
+                    self.command.command_line_lossy(),
+                )?;
+                match signal_name(*signal) {
+                    Some(name) => write!(f, "{} ({})", name, signal),
+                    None => write!(f, "{}", signal),
+                }
+            }
+            ErrorKind::Timeout(elapsed) => write!(
                 f,
-                "command '{}' failed: {}",
+                "command '{}' timed out after {:?}",
+                self.command.command_line_lossy(),
+                elapsed
+            ),
+            ErrorKind::OutputLimit(limit) => write!(
+                f,
+                "command '{}' produced more than {} lines of output",
+                self.command.command_line_lossy(),
+                limit
+            ),
+            #[cfg(feature = "serde_json")]
+            ErrorKind::Parse(err) => write!(
+                f,
+                "failed to parse output of '{}': {}",
                 self.command.command_line_lossy(),
                 err
             ),
@@ -87,7 +230,71 @@ impl fmt::Display for Error {
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Run(err) => Some(err),
+            #[cfg(feature = "serde_json")]
+            ErrorKind::Parse(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Error returned by [`Command::parse`].
+#[cfg(feature = "shell-words")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ParseError {
+    /// The string is empty or only contains whitespace.
+    Empty,
+
+    /// A quoted string is missing its closing quote.
+    MissingClosingQuote,
+}
+
+#[cfg(feature = "shell-words")]
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            ParseError::Empty => write!(f, "empty command"),
+            ParseError::MissingClosingQuote => {
+                write!(f, "missing closing quote")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "shell-words")]
+impl std::error::Error for ParseError {}
+
+/// Get the name of a common signal, such as `"SIGKILL"`.
+#[cfg(unix)]
+fn signal_name(signal: i32) -> Option<&'static str> {
+    Some(match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        _ => return None,
+    })
+}
+
+/// Signals are only used on Unix.
+#[cfg(not(unix))]
+fn signal_name(_signal: i32) -> Option<&'static str> {
+    None
+}
 
 /// The output of a finished process.
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -100,18 +307,162 @@ pub struct Output {
 
     /// The data that the process wrote to stderr.
     pub stderr: Vec<u8>,
+
+    /// The data that the process wrote to stdout and stderr, in the
+    /// order it was received. Each chunk is tagged with the stream it
+    /// was written to. This is empty unless `capture_tagged` is
+    /// `true`.
+    pub tagged_output: Vec<(Stream, Vec<u8>)>,
+
+    /// How long the process ran for, measured from when it was
+    /// started until it exited.
+    pub duration: Duration,
+
+    /// The command that produced this output. This is set by
+    /// [`Command::run`].
+    pub command: Option<Command>,
 }
 
 impl Output {
+    /// Get the exit code of the process.
+    ///
+    /// See [`std::process::ExitStatus::code`].
+    pub fn code(&self) -> Option<i32> {
+        self.status.code()
+    }
+
+    /// Get the raw wait status of the process, which encodes either
+    /// the exit code or the signal that terminated the process.
+    ///
+    /// See [`std::os::unix::process::ExitStatusExt::into_raw`].
+    #[cfg(unix)]
+    pub fn raw_status(&self) -> i32 {
+        use std::os::unix::process::ExitStatusExt;
+        self.status.into_raw()
+    }
+
+    /// Check if the process exited successfully.
+    ///
+    /// See [`std::process::ExitStatus::success`].
+    pub fn success(&self) -> bool {
+        self.status.success()
+    }
+
+    /// Check if stdout is empty.
+    pub fn stdout_is_empty(&self) -> bool {
+        self.stdout.is_empty()
+    }
+
+    /// Check if stderr is empty.
+    pub fn stderr_is_empty(&self) -> bool {
+        self.stderr.is_empty()
+    }
+
+    /// Get the length of stdout in bytes.
+    pub fn stdout_len(&self) -> usize {
+        self.stdout.len()
+    }
+
+    /// Get stdout as a string, or an error if it is not valid UTF-8.
+    pub fn stdout_string(&self) -> Result<String, FromUtf8Error> {
+        String::from_utf8(self.stdout.clone())
+    }
+
+    /// Get stderr as a string, or an error if it is not valid UTF-8.
+    pub fn stderr_string(&self) -> Result<String, FromUtf8Error> {
+        String::from_utf8(self.stderr.clone())
+    }
+
     /// Get stdout as a string.
-    pub fn stdout_string_lossy(&self) -> Cow<str> {
+    pub fn stdout_string_lossy(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.stdout)
     }
 
     /// Get stderr as a string.
-    pub fn stderr_string_lossy(&self) -> Cow<str> {
+    pub fn stderr_string_lossy(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.stderr)
     }
+
+    /// Get stdout as an `OsString`.
+    ///
+    /// On Unix the bytes are used as-is, so output that is not valid
+    /// UTF-8 (such as some file paths) is preserved. On other
+    /// platforms the output is converted with
+    /// [`String::from_utf8_lossy`].
+    pub fn stdout_osstring(&self) -> OsString {
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            OsStr::from_bytes(&self.stdout).to_os_string()
+        }
+        #[cfg(not(unix))]
+        {
+            self.stdout_string_lossy().into_owned().into()
+        }
+    }
+
+    /// Get stdout as a string with trailing whitespace removed.
+    pub fn stdout_string_lossy_trimmed(&self) -> Cow<'_, str> {
+        trim_end(self.stdout_string_lossy())
+    }
+
+    /// Get stderr as a string with trailing whitespace removed.
+    pub fn stderr_string_lossy_trimmed(&self) -> Cow<'_, str> {
+        trim_end(self.stderr_string_lossy())
+    }
+
+    /// Get stdout as a string, decoded with `encoding` instead of
+    /// UTF-8. Malformed sequences are replaced with the replacement
+    /// character.
+    #[cfg(feature = "encoding")]
+    pub fn stdout_string_with_encoding(
+        &self,
+        encoding: &'static encoding_rs::Encoding,
+    ) -> Cow<'_, str> {
+        encoding.decode_without_bom_handling(&self.stdout).0
+    }
+
+    /// Iterate over the lines of stdout. Lines end with `\n` or
+    /// `\r\n`, which is not included in the line. If stdout ends
+    /// with a newline, no empty line is produced for the end.
+    ///
+    /// Each line is converted to a string with
+    /// [`String::from_utf8_lossy`].
+    pub fn stdout_lines(&self) -> impl Iterator<Item = Cow<'_, str>> {
+        let stdout = self.stdout.strip_suffix(b"\n").unwrap_or(&self.stdout);
+        let lines = if self.stdout.is_empty() {
+            None
+        } else {
+            Some(stdout.split(|b| *b == b'\n'))
+        };
+        lines
+            .into_iter()
+            .flatten()
+            .map(|line| String::from_utf8_lossy(strip_newline(line)))
+    }
+
+    /// Iterate over the parts of stdout separated by NUL bytes, as
+    /// produced by tools like `find -print0`. If stdout ends with a
+    /// NUL byte, no empty part is produced for the end.
+    pub fn stdout_split_nul(&self) -> impl Iterator<Item = &[u8]> {
+        let stdout = self.stdout.strip_suffix(b"\0").unwrap_or(&self.stdout);
+        let parts = if self.stdout.is_empty() {
+            None
+        } else {
+            Some(stdout.split(|b| *b == 0))
+        };
+        parts.into_iter().flatten()
+    }
+
+    /// Get stdout with at most one trailing newline removed. A `\r`
+    /// directly before the newline is removed as well.
+    pub fn stdout_no_trailing_newline(&self) -> &[u8] {
+        let out = self.stdout.as_slice();
+        match out.strip_suffix(b"\n") {
+            Some(out) => out.strip_suffix(b"\r").unwrap_or(out),
+            None => out,
+        }
+    }
 }
 
 impl From<process::Output> for Output {
@@ -120,181 +471,1315 @@ impl From<process::Output> for Output {
             status: o.status,
             stdout: o.stdout,
             stderr: o.stderr,
+            tagged_output: Vec::new(),
+            duration: Duration::ZERO,
+            command: None,
         }
     }
 }
 
-fn combine_output(mut cmd: process::Command) -> Result<Output, io::Error> {
-    let (mut reader, writer) = os_pipe::pipe()?;
-    let writer_clone = writer.try_clone()?;
-    cmd.stdout(writer);
-    cmd.stderr(writer_clone);
-
-    let mut handle = cmd.spawn()?;
-
-    drop(cmd);
+/// Result of [`Command::run_typed`].
+// The size difference is not a concern next to the cost of running a
+// process.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RunResult {
+    /// The output was captured.
+    Captured(Output),
 
-    let mut output = Vec::new();
-    reader.read_to_end(&mut output)?;
-    let status = handle.wait()?;
+    /// The output was not captured, so only the status is available.
+    Status(process::ExitStatus),
+}
 
-    Ok(Output {
-        stdout: output,
-        stderr: Vec::new(),
-        status,
-    })
+/// Remove trailing whitespace from `s`.
+fn trim_end(s: Cow<'_, str>) -> Cow<'_, str> {
+    match s {
+        Cow::Borrowed(s) => Cow::Borrowed(s.trim_end()),
+        Cow::Owned(s) => Cow::Owned(s.trim_end().to_string()),
+    }
 }
 
-/// Where log messages go.
+/// An output stream of a process.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
-pub enum LogTo {
-    /// Print to stdout.
+pub enum Stream {
+    /// Standard output.
     Stdout,
 
-    /// Use the standard `log` crate.
-    #[cfg(feature = "logging")]
-    Log,
+    /// Standard error.
+    Stderr,
 }
 
-/// A command to run in a subprocess and options for how it is run.
-///
-/// Some notable trait implementations:
-/// - Derives [`Clone`], [`Debug`], [`Eq`], and [`PartialEq`]
-/// - [`Default`] (see docstrings for each field for what the
-///   corresponding default is)
-/// - `From<&Command> for std::process::Command` to convert to a
-///   [`std::process::Command`]
+/// Callback that receives a line of output from a command.
 ///
-/// [`Debug`]: std::fmt::Debug
-#[derive(Clone, Debug, Eq, PartialEq)]
-#[must_use]
-pub struct Command {
-    /// Program path.
-    ///
-    /// The path can be just a file name, in which case the `$PATH` is
-    /// searched.
-    pub program: PathBuf,
+/// The line does not include the trailing newline.
+pub type LineCallback = Arc<dyn Fn(&str) + Send + Sync>;
 
-    /// Arguments passed to the program.
-    pub args: Vec<OsString>,
+/// Callback that receives a [`Command`].
+pub type CommandCallback = Arc<dyn Fn(&Command) + Send + Sync>;
 
-    /// Directory from which to run the program.
-    ///
-    /// If not set (the default), the current working directory is
-    /// used.
-    pub dir: Option<PathBuf>,
+/// Callback that receives a [`Command`] and the result of running it.
+pub type ResultCallback =
+    Arc<dyn Fn(&Command, &Result<Output, Error>) + Send + Sync>;
 
-    /// Where log messages go. The default is stdout.
-    pub log_to: LogTo,
+/// Callback that modifies a [`std::process::Command`].
+pub type StdCommandCallback = Arc<dyn Fn(&mut process::Command) + Send + Sync>;
 
-    /// If `true` (the default), log the command before running it.
-    pub log_command: bool,
+/// Callback that decides whether an exit status is a success.
+pub type StatusCallback =
+    Arc<dyn Fn(&process::ExitStatus) -> bool + Send + Sync>;
 
-    /// If `true`, log the output if the command exits non-zero or due
-    /// to a signal. This does nothing is `capture` is `false` or if
-    /// `check` is `false`. The default is `false`.
-    pub log_output_on_error: bool,
+/// Output chunks tagged with the stream they came from.
+type TaggedChunks = Arc<Mutex<Vec<(Stream, Vec<u8>)>>>;
 
-    /// If `true` (the default), check if the command exited
-    /// successfully and return an error if not.
-    pub check: bool,
+/// Handle to a thread started with `read_in_thread`.
+#[derive(Debug)]
+struct ReaderHandle {
+    thread: thread::JoinHandle<io::Result<()>>,
 
-    /// If `true`, capture the stdout and stderr of the
-    /// command. The default is `false`.
-    pub capture: bool,
+    /// The data read so far.
+    output: Arc<Mutex<Vec<u8>>>,
 
-    /// If `true`, send stderr to stdout; the `stderr` field in
-    /// `Output` will be empty. The default is `false.`
-    pub combine_output: bool,
+    /// If set, only the last `n` bytes of `output` are returned.
+    tail: Option<usize>,
+}
 
-    /// If `false` (the default), inherit environment variables from the
+/// Options for `read_in_thread`.
+struct ReaderOptions {
+    /// If `false`, the data is not returned.
+    keep: bool,
+
+    /// If set, this is called with each line as soon as the line has
+    /// been read.
+    on_line: Option<LineCallback>,
+
+    /// If set, each chunk of data is appended to the list along with
+    /// the stream it was read from.
+    tagged: Option<(Stream, TaggedChunks)>,
+
+    /// If set, each chunk of data is written to this stream of the
     /// current process.
-    pub clear_env: bool,
+    tee: Option<Stream>,
 
-    /// Add or update environment variables in the child process.
-    pub env: HashMap<OsString, OsString>,
+    /// If `true`, the data is read line by line and each line written
+    /// to `tee` is prefixed with the current time. This is only set if
+    /// `tee` is set.
+    #[cfg(feature = "time")]
+    tee_timestamps: bool,
+
+    /// If set, only the last `n` bytes of data are kept.
+    tail: Option<usize>,
+
+    /// If set, this is incremented for each line of data that is
+    /// kept.
+    line_count: Option<Arc<AtomicUsize>>,
 }
 
-impl Command {
-    /// Make a new `Command` with the given program.
-    ///
-    /// All other fields are set to the defaults.
-    pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
-        Self {
-            program: program.as_ref().into(),
-            ..Default::default()
-        }
-    }
+/// Read all data from `reader` in a background thread.
+fn read_in_thread<R: Read + Send + 'static>(
+    reader: R,
+    opts: ReaderOptions,
+) -> ReaderHandle {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let thread_output = output.clone();
+    let tail = opts.tail;
+    let thread = thread::spawn(move || {
+        let mut reader = io::BufReader::new(reader);
+        let mut chunk = Vec::new();
+        #[cfg(feature = "time")]
+        let by_line = opts.on_line.is_some() || opts.tee_timestamps;
+        #[cfg(not(feature = "time"))]
+        let by_line = opts.on_line.is_some();
+        loop {
+            chunk.clear();
+            if by_line {
+                reader.read_until(b'\n', &mut chunk)?;
+            } else {
+                chunk.extend_from_slice(reader.fill_buf()?);
+                reader.consume(chunk.len());
+            }
+            if chunk.is_empty() {
+                break;
+            }
 
-    /// Make a new `Command` with the given program and args.
-    ///
-    /// All other fields are set to the defaults.
-    pub fn with_args<I, S1, S2>(program: S1, args: I) -> Self
-    where
-        S1: AsRef<OsStr>,
-        S2: AsRef<OsStr>,
-        I: IntoIterator<Item = S2>,
-    {
-        Self {
-            program: program.as_ref().into(),
-            args: args.into_iter().map(|arg| arg.as_ref().into()).collect(),
-            ..Default::default()
+            if let Some(on_line) = &opts.on_line {
+                on_line(&String::from_utf8_lossy(strip_newline(&chunk)));
+            }
+            #[cfg(feature = "time")]
+            let timestamped;
+            #[cfg(feature = "time")]
+            let tee_chunk = if opts.tee_timestamps {
+                timestamped =
+                    [format!("{} ", timestamp()).as_bytes(), &chunk].concat();
+                &timestamped
+            } else {
+                &chunk
+            };
+            #[cfg(not(feature = "time"))]
+            let tee_chunk = &chunk;
+            match opts.tee {
+                // Errors writing the copy are ignored.
+                Some(Stream::Stdout) => {
+                    let _ = io::stdout().write_all(tee_chunk);
+                }
+                Some(Stream::Stderr) => {
+                    let _ = io::stderr().write_all(tee_chunk);
+                }
+                None => {}
+            }
+            if let Some((stream, tagged)) = &opts.tagged {
+                tagged.lock().unwrap().push((*stream, chunk.clone()));
+            }
+            if let (true, Some(line_count)) = (opts.keep, &opts.line_count) {
+                let lines = chunk.iter().filter(|b| **b == b'\n').count();
+                line_count.fetch_add(lines, Ordering::Relaxed);
+            }
+            if opts.keep {
+                let mut output = thread_output.lock().unwrap();
+                output.extend_from_slice(&chunk);
+                // Only trim once the buffer has grown to twice the
+                // limit, so that data is not moved on every read.
+                if let Some(tail) = tail {
+                    if output.len() >= tail.saturating_mul(2) {
+                        keep_tail(&mut output, tail);
+                    }
+                }
+            }
         }
+        Ok(())
+    });
+    ReaderHandle {
+        thread,
+        output,
+        tail,
     }
+}
 
-    /// Create a `Command` from a whitespace-separated string. If the
-    /// string is empty or all whitespace, `None` is returned.
-    ///
-    /// This function does not do unquoting or escaping.
-    pub fn from_whitespace_separated_str(s: &str) -> Option<Self> {
-        let mut parts = s.split_whitespace();
-        let program = parts.next()?;
-        Some(Self::with_args(program, parts))
+/// Remove data from the start of `buf` so that at most `tail` bytes
+/// remain.
+fn keep_tail(buf: &mut Vec<u8>, tail: usize) {
+    if buf.len() > tail {
+        buf.drain(..buf.len() - tail);
     }
+}
 
-    /// Append a single argument.
-    pub fn add_arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
-        self.args.push(arg.as_ref().into());
-        self
+/// Replace each `\r\n` in `buf` with `\n`.
+fn normalize_newlines(buf: &mut Vec<u8>) {
+    let mut len = 0;
+    for i in 0..buf.len() {
+        if buf[i] == b'\r' && buf.get(i + 1) == Some(&b'\n') {
+            continue;
+        }
+        buf[len] = buf[i];
+        len += 1;
     }
+    buf.truncate(len);
+}
 
-    /// Append two arguments.
-    ///
-    /// This is equivalent to calling `add_arg` twice; it is for the
-    /// common case where the arguments have different types, e.g. a
-    /// literal string for the first argument and a `Path` for the
-    /// second argument.
-    pub fn add_arg_pair<S1, S2>(&mut self, arg1: S1, arg2: S2) -> &mut Self
-    where
-        S1: AsRef<OsStr>,
-        S2: AsRef<OsStr>,
-    {
-        self.add_arg(arg1);
-        self.add_arg(arg2);
-        self
+/// Remove a trailing `\n` or `\r\n` from `line`.
+fn strip_newline(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Treat a broken pipe error as success.
+///
+/// This is used when writing to the stdin of a child process, since
+/// the child may exit without reading all of its input.
+fn ignore_broken_pipe(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
     }
+}
 
-    /// Append multiple arguments.
-    pub fn add_args<I, S>(&mut self, args: I) -> &mut Self
-    where
-        S: AsRef<OsStr>,
-        I: IntoIterator<Item = S>,
-    {
-        for arg in args {
-            self.add_arg(arg);
+/// Get the data read by a thread started with `read_in_thread`.
+fn join_reader(handle: Option<ReaderHandle>) -> Result<Vec<u8>, io::Error> {
+    match handle {
+        Some(handle) => {
+            handle.thread.join().expect("reader thread panicked")?;
+            let mut output = mem::take(&mut *handle.output.lock().unwrap());
+            if let Some(tail) = handle.tail {
+                keep_tail(&mut output, tail);
+            }
+            Ok(output)
         }
-        self
+        None => Ok(Vec::new()),
     }
+}
 
-    /// Set `capture` to `true`.
-    pub fn enable_capture(&mut self) -> &mut Self {
-        self.capture = true;
-        self
-    }
+/// Get a copy of the data read so far by a thread started with
+/// `read_in_thread`, without waiting for the thread to finish.
+fn reader_output_so_far(handle: &Option<ReaderHandle>) -> Vec<u8> {
+    handle
+        .as_ref()
+        .map(|handle| {
+            let mut output = handle.output.lock().unwrap().clone();
+            if let Some(tail) = handle.tail {
+                keep_tail(&mut output, tail);
+            }
+            output
+        })
+        .unwrap_or_default()
+}
 
-    /// Set `combine_output` to `true`.
-    pub fn combine_output(&mut self) -> &mut Self {
-        self.combine_output = true;
+/// Why `wait_with_timeout` killed the child.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum KillReason {
+    Timeout,
+    OutputLimit,
+}
+
+/// Wait for the child to exit.
+///
+/// If `timeout` is set and elapses before the child exits, or if
+/// `over_limit` is set and returns `true`, the child is killed. If
+/// `kill_group` is `true`, the child's process group is killed. The
+/// returned reason is set if the child was killed. See
+/// `terminate_child` for how `kill_grace` is used on timeout.
+///
+/// If `heartbeat` is set, its callback is called with the elapsed
+/// time each time the interval passes while the child is running.
+fn wait_with_timeout(
+    child: &mut process::Child,
+    start: Instant,
+    timeout: Option<Duration>,
+    kill_group: bool,
+    kill_grace: Option<Duration>,
+    heartbeat: Option<(Duration, &dyn Fn(Duration))>,
+    over_limit: Option<&dyn Fn() -> bool>,
+) -> Result<(process::ExitStatus, Option<KillReason>), io::Error> {
+    if timeout.is_none() && heartbeat.is_none() && over_limit.is_none() {
+        return Ok((child.wait()?, None));
+    }
+
+    let mut next_heartbeat = heartbeat.map(|(interval, _)| interval);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok((status, None));
+        }
+        let elapsed = start.elapsed();
+        let mut sleep = Duration::from_millis(10);
+        if let Some(timeout) = timeout {
+            if elapsed >= timeout {
+                let status = terminate_child(child, kill_group, kill_grace)?;
+                return Ok((status, Some(KillReason::Timeout)));
+            }
+            sleep = sleep.min(timeout - elapsed);
+        }
+        if over_limit.is_some_and(|over_limit| over_limit()) {
+            kill_child(child, kill_group)?;
+            return Ok((child.wait()?, Some(KillReason::OutputLimit)));
+        }
+        if let (Some((interval, on_heartbeat)), Some(next)) =
+            (heartbeat, &mut next_heartbeat)
+        {
+            if elapsed >= *next {
+                on_heartbeat(elapsed);
+                *next += interval;
+            }
+        }
+        thread::sleep(sleep);
+    }
+}
+
+/// Set the close-on-exec flag on all file descriptors starting at
+/// `first`, so that they are closed when the child calls `exec`.
+///
+/// The descriptors are not closed directly, since the standard
+/// library uses a close-on-exec pipe to report `exec` errors.
+///
+/// This is called between `fork` and `exec`, so it must only call
+/// async-signal-safe functions.
+#[cfg(unix)]
+fn set_cloexec_from(first: libc::c_int, max_fd: libc::c_int) {
+    #[cfg(target_os = "linux")]
+    {
+        // SAFETY: `close_range` with `CLOSE_RANGE_CLOEXEC` only
+        // changes descriptor flags.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_close_range,
+                first as libc::c_uint,
+                libc::c_uint::MAX,
+                libc::CLOSE_RANGE_CLOEXEC,
+            )
+        };
+        if ret == 0 {
+            return;
+        }
+    }
+
+    // Fall back to checking each descriptor if `close_range` is not
+    // available. The open file limit can be very large, so only a
+    // limited number of descriptors are checked.
+    for fd in first..max_fd.min(CLOSE_FDS_MAX) {
+        // SAFETY: `fcntl` is safe to call with any descriptor; invalid
+        // descriptors return an error, which is ignored.
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags >= 0 && flags & libc::FD_CLOEXEC == 0 {
+                libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC);
+            }
+        }
+    }
+}
+
+/// Descriptors at or above this are not checked by
+/// `set_cloexec_from` when `close_range` is not available.
+#[cfg(unix)]
+const CLOSE_FDS_MAX: libc::c_int = 65536;
+
+/// Return `path` if it is an executable file. On platforms other
+/// than Unix, `path` with `.exe` appended is also checked.
+fn executable_path(path: PathBuf) -> Option<PathBuf> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let metadata = fs::metadata(&path).ok()?;
+        (metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+            .then_some(path)
+    }
+    #[cfg(not(unix))]
+    {
+        if path.is_file() {
+            return Some(path);
+        }
+        let mut exe = path.into_os_string();
+        exe.push(".exe");
+        let exe = PathBuf::from(exe);
+        exe.is_file().then_some(exe)
+    }
+}
+
+/// Controller side of a pseudo-terminal.
+#[cfg(all(unix, feature = "pty"))]
+struct PtyReader(fs::File);
+
+#[cfg(all(unix, feature = "pty"))]
+impl Read for PtyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            // On Linux, reading fails with `EIO` once all handles to
+            // the terminal are closed. Treat that as end-of-file.
+            Err(err) if err.raw_os_error() == Some(libc::EIO) => Ok(0),
+            result => result,
+        }
+    }
+}
+
+/// Open a new pseudo-terminal. Returns a reader for the controller
+/// side and the terminal device.
+#[cfg(all(unix, feature = "pty"))]
+fn open_pty() -> io::Result<(PtyReader, fs::File)> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    // `ptsname` is not thread safe.
+    static PTSNAME_LOCK: Mutex<()> = Mutex::new(());
+
+    // SAFETY: these functions have no memory safety requirements. The
+    // file descriptor returned by `posix_openpt` is owned by `File`,
+    // and the string returned by `ptsname` is copied while holding
+    // the lock.
+    let (controller, path) = unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let controller = fs::File::from_raw_fd(fd);
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) != 0
+            || libc::grantpt(fd) != 0
+            || libc::unlockpt(fd) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        let _guard = PTSNAME_LOCK.lock().unwrap();
+        let name = libc::ptsname(controller.as_raw_fd());
+        if name.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let name = std::ffi::CStr::from_ptr(name);
+        let path = PathBuf::from(OsStr::from_bytes(name.to_bytes()));
+        (controller, path)
+    };
+
+    let terminal = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)?;
+    Ok((PtyReader(controller), terminal))
+}
+
+/// Kill `child`. If `kill_group` is `true` and this is Unix, kill the
+/// child's process group instead.
+///
+/// The process group is only killed if the child has not been reaped
+/// yet. Once reaped, its pid (and so the process group ID) may have
+/// been reused by an unrelated process.
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn kill_child(child: &mut process::Child, kill_group: bool) -> io::Result<()> {
+    #[cfg(unix)]
+    if kill_group {
+        if child.try_wait()?.is_some() {
+            return Ok(());
+        }
+        return kill_process_group(child.id());
+    }
+    child.kill()
+}
+
+/// Stop `child` and wait for it to exit.
+///
+/// If `grace` is set and this is Unix, `SIGTERM` is sent first, and
+/// the child is only killed if it is still running after `grace` has
+/// elapsed. If `kill_group` is `true`, signals are sent to the child's
+/// process group instead.
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn terminate_child(
+    child: &mut process::Child,
+    kill_group: bool,
+    grace: Option<Duration>,
+) -> io::Result<process::ExitStatus> {
+    #[cfg(unix)]
+    if let Some(grace) = grace {
+        let pid = child.id() as libc::pid_t;
+        let pid = if kill_group { -pid } else { pid };
+        // SAFETY: `kill` has no memory safety requirements.
+        if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let start = Instant::now();
+        while start.elapsed() < grace {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    kill_child(child, kill_group)?;
+    child.wait()
+}
+
+/// Kill the process group whose ID is `pgid`.
+#[cfg(unix)]
+fn kill_process_group(pgid: u32) -> io::Result<()> {
+    // SAFETY: `kill` has no memory safety requirements.
+    let ret = unsafe { libc::kill(-(pgid as libc::pid_t), libc::SIGKILL) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Get the kind of error for a command that exited unsuccessfully
+/// with `status`.
+fn exit_error_kind(status: process::ExitStatus) -> ErrorKind {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(signal) = status.signal() {
+            return ErrorKind::Signal(signal);
+        }
+    }
+    ErrorKind::Exit(status)
+}
+
+/// Severity of an internal log message.
+#[derive(Clone, Copy)]
+enum LogLevel {
+    /// Used for the command line. When using the `log` crate, the
+    /// level is taken from `Command::log_level`.
+    Command,
+    /// Used for the output of a successful command.
+    Output,
+    Error,
+}
+
+/// Where the stdin of a command comes from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StdinSource {
+    /// Inherit stdin from the current process.
+    Inherit,
+
+    /// Connect stdin to the null device, so reads immediately reach
+    /// end-of-file.
+    Null,
+
+    /// Write these bytes to stdin, then close it.
+    Bytes(Vec<u8>),
+
+    /// Read stdin from this file.
+    File(PathBuf),
+}
+
+/// Where the stdin, stdout, or stderr of a command is connected.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StreamConfig {
+    /// Inherit the stream from the current process.
+    Inherit,
+
+    /// Connect the stream to the null device. Output is discarded,
+    /// and reads from stdin immediately reach end-of-file.
+    Null,
+
+    /// Capture the output in [`Output`]. This is not valid for stdin.
+    Capture,
+
+    /// Write the output to this file, or read stdin from it. An
+    /// output file is created if it does not exist and truncated if
+    /// it does (unless `stdout_file_append` or `stderr_file_append` is
+    /// `true`).
+    File(PathBuf),
+
+    /// Send stderr to the same place as stdout. If stdout is
+    /// captured, the combined output is captured in
+    /// `Output::stdout`. This is only valid for stderr.
+    Stdout,
+}
+
+/// Shared writer that log messages can be sent to.
+pub type LogWriter = Arc<Mutex<dyn Write + Send>>;
+
+/// Where log messages go.
+///
+/// Two `Writer` values are equal if they point to the same writer.
+#[derive(Clone)]
+pub enum LogTo {
+    /// Print to stdout.
+    Stdout,
+
+    /// Use the standard `log` crate.
+    #[cfg(feature = "logging")]
+    Log,
+
+    /// Use the `tracing` crate. Messages are emitted as events at the
+    /// `INFO` level, or `ERROR` for failed commands. When running with
+    /// [`Command::run`], the events are emitted within a `command`
+    /// span that records the command line.
+    #[cfg(feature = "tracing")]
+    Tracing,
+
+    /// Write each message to the writer, followed by a newline. Errors
+    /// writing the message are ignored.
+    Writer(LogWriter),
+}
+
+impl fmt::Debug for LogTo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            LogTo::Stdout => write!(f, "Stdout"),
+            #[cfg(feature = "logging")]
+            LogTo::Log => write!(f, "Log"),
+            #[cfg(feature = "tracing")]
+            LogTo::Tracing => write!(f, "Tracing"),
+            LogTo::Writer(_) => write!(f, "Writer(<writer>)"),
+        }
+    }
+}
+
+impl PartialEq for LogTo {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (LogTo::Stdout, LogTo::Stdout) => true,
+            #[cfg(feature = "logging")]
+            (LogTo::Log, LogTo::Log) => true,
+            #[cfg(feature = "tracing")]
+            (LogTo::Tracing, LogTo::Tracing) => true,
+            (LogTo::Writer(a), LogTo::Writer(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for LogTo {}
+
+/// A command to run in a subprocess and options for how it is run.
+///
+/// Some notable trait implementations:
+/// - Derives [`Clone`]
+/// - [`Debug`], [`Eq`], and [`PartialEq`] (callback fields such as
+///   `on_stdout_line`, `before_run`, and `success_predicate` are not
+///   compared)
+/// - [`Default`] (see docstrings for each field for what the
+///   corresponding default is)
+/// - `From<&Command> for std::process::Command` to convert to a
+///   [`std::process::Command`]
+///
+/// [`Debug`]: std::fmt::Debug
+#[derive(Clone)]
+#[must_use]
+pub struct Command {
+    /// Program path.
+    ///
+    /// The path can be just a file name, in which case the `$PATH` is
+    /// searched.
+    pub program: PathBuf,
+
+    /// Arguments passed to the program.
+    pub args: Vec<OsString>,
+
+    /// If set, the first element of the argument vector passed to the
+    /// program, instead of the program path. This only has an effect
+    /// on Unix. The default is `None`.
+    pub arg0: Option<OsString>,
+
+    /// If `true`, occurrences of `${NAME}` in `args` are replaced with
+    /// the value of the environment variable `NAME` as seen by the
+    /// child process: `env_remove`, `path_override`, `env_ordered`,
+    /// and `env` are applied, then the current process's environment
+    /// is checked (unless `clear_env` is `true` and `NAME` is not in
+    /// `env_inherit`). The default is `false`.
+    pub expand_args: bool,
+
+    /// If `true` and `expand_args` is set, running the command fails
+    /// with [`ErrorKind::Run`] if an argument refers to an undefined
+    /// variable. Otherwise undefined variables expand to an empty
+    /// string. The default is `false`.
+    pub expand_args_strict: bool,
+
+    /// If set, run the command with this shell instead of running
+    /// `program` directly. The shell is passed `-c` followed by
+    /// [`Command::command_line_shell`], so shell builtins and
+    /// functions can be used as the program. `arg0` and
+    /// `expand_args` are ignored in this case. The default is `None`.
+    pub via_shell: Option<OsString>,
+
+    /// Directory from which to run the program.
+    ///
+    /// If not set (the default), the current working directory is
+    /// used.
+    pub dir: Option<PathBuf>,
+
+    /// If `true`, check that `dir` is an existing directory before
+    /// running the program, and return a descriptive error if not.
+    /// The default is `false`.
+    pub verify_dir: bool,
+
+    /// If `true`, create `dir` (including any missing parent
+    /// directories) before running the program. The default is
+    /// `false`.
+    pub create_dir: bool,
+
+    /// Where log messages go. The default is stdout.
+    pub log_to: LogTo,
+
+    /// Characters other than ASCII alphanumerics that do not cause a
+    /// component to be quoted by [`Command::command_line_lossy`]. The
+    /// default is `/-_,:.=+`.
+    pub command_line_allowed_chars: Cow<'static, str>,
+
+    /// If `true`, each line of a log message sent to stdout or a
+    /// writer is prefixed with the current time in UTC, formatted as
+    /// an RFC 3339 timestamp. This does nothing when logging with the
+    /// `log` or `tracing` crates. Each line of output copied by
+    /// `tee_output` is prefixed in the same way. The default is
+    /// `false`.
+    #[cfg(feature = "time")]
+    pub log_timestamps: bool,
+
+    /// If `true` (the default), log the command before running it.
+    pub log_command: bool,
+
+    /// The level at which the command is logged when `log_to` is
+    /// `LogTo::Log`. The default is `Level::Info`.
+    ///
+    /// If the `structured_logging` feature is enabled, the log record
+    /// also has these key/value pairs:
+    /// - `program`: the program path
+    /// - `args`: the number of arguments
+    /// - `dir`: the directory, or an empty string if `dir` is not set
+    #[cfg(feature = "logging")]
+    pub log_level: log::Level,
+
+    /// If `true`, log the output if the command exits non-zero or due
+    /// to a signal. This does nothing is `capture` is `false` or if
+    /// `check` is `false`. The default is `false`.
+    pub log_output_on_error: bool,
+
+    /// If `true`, log the output after every run, not just when the
+    /// command fails. Output of a successful command is logged at the
+    /// info level. This does nothing if `capture` is `false`. The
+    /// default is `false`.
+    pub log_output_always: bool,
+
+    /// If `true`, [`Command::run`] logs the command (if `log_command`
+    /// is `true`) but does not run it, and returns a successful
+    /// `Output` with empty stdout and stderr. [`Command::spawn`]
+    /// returns an error instead, since there is no child process to
+    /// return. The default is `false`.
+    pub dry_run: bool,
+
+    /// If set, this is called with the command each time it would
+    /// have been run in `dry_run` mode, after the command is logged.
+    /// This can be used to record the commands. The default is
+    /// `None`.
+    pub dry_run_sink: Option<CommandCallback>,
+
+    /// If `true` (the default), check if the command exited
+    /// successfully and return an error if not.
+    pub check: bool,
+
+    /// Exit codes that are treated as success by `check`.
+    ///
+    /// If `None` (the default), only an exit code of zero is treated
+    /// as success.
+    pub success_codes: Option<Vec<i32>>,
+
+    /// If set, this is called to decide whether an exit status is
+    /// treated as success by `check`. This takes precedence over
+    /// `success_codes`. The default is `None`.
+    pub success_predicate: Option<StatusCallback>,
+
+    /// Number of times to run the command again if it fails. This
+    /// does nothing if `check` is `false`. The default is zero.
+    pub retries: u32,
+
+    /// Time to wait before running the command again after a failure.
+    /// The default is `None`.
+    pub retry_delay: Option<Duration>,
+
+    /// If set, the time to wait before running the command again
+    /// grows after each failure. The first value is the delay before
+    /// the first retry, and each following delay is multiplied by the
+    /// second value. This takes precedence over `retry_delay`. The
+    /// default is `None`.
+    pub retry_backoff: Option<(Duration, f64)>,
+
+    /// The maximum delay between attempts when using
+    /// `retry_backoff`. The default is `None`.
+    pub retry_backoff_max: Option<Duration>,
+
+    /// If `true`, a command that is killed due to `timeout` may also
+    /// be run again. The default is `false`.
+    pub retry_on_timeout: bool,
+
+    /// If `true`, capture the stdout and stderr of the
+    /// command. `stdout_config` and `stderr_config` can be used to
+    /// configure each stream separately. The default is `false`.
+    pub capture: bool,
+
+    /// If `true` and both stdout and stderr are captured, send stderr
+    /// to stdout; the `stderr` field in `Output` will be empty. This
+    /// is the same as setting `stderr_config` to
+    /// [`StreamConfig::Stdout`] in that case. The default is `false.`
+    pub combine_output: bool,
+
+    /// If `true`, stderr goes to the parent's stderr instead of being
+    /// captured, so it is shown as the command runs. The `stderr`
+    /// field in `Output` will be empty. This cannot be combined with
+    /// `combine_output`. The default is `false`.
+    pub stderr_to_parent: bool,
+
+    /// If set, the command's stdout is written to this file. The file
+    /// is created if it does not exist and truncated if it does
+    /// (unless `stdout_file_append` is `true`). This cannot be
+    /// combined with `capture`. The default is `None`.
+    pub stdout_file: Option<PathBuf>,
+
+    /// If `true`, append to `stdout_file` instead of truncating
+    /// it. The default is `false`.
+    pub stdout_file_append: bool,
+
+    /// If set, the command's stderr is written to this file. The file
+    /// is created if it does not exist and truncated if it does
+    /// (unless `stderr_file_append` is `true`). This cannot be
+    /// combined with `capture`. The default is `None`.
+    pub stderr_file: Option<PathBuf>,
+
+    /// If `true`, append to `stderr_file` instead of truncating
+    /// it. The default is `false`.
+    pub stderr_file_append: bool,
+
+    /// If set, where the command's stdout goes. This takes precedence
+    /// over `capture` and `stdout_file`. The default is `None`.
+    pub stdout_config: Option<StreamConfig>,
+
+    /// If set, where the command's stderr goes. This takes precedence
+    /// over `capture`, `stderr_to_parent`, and `stderr_file`. The
+    /// default is `None`.
+    pub stderr_config: Option<StreamConfig>,
+
+    /// If set, where the command's stdin comes from. This takes
+    /// precedence over `stdin`. `Capture` and `Stdout` are not valid
+    /// here. The default is `None`.
+    pub stdin_config: Option<StreamConfig>,
+
+    /// Where the command's stdin comes from. The default is
+    /// `StdinSource::Inherit`.
+    ///
+    /// To match the behavior of [`std::process::Command::output`],
+    /// `Inherit` acts like `Null` if `capture` is `true` and
+    /// `combine_output` is `false`.
+    pub stdin: StdinSource,
+
+    /// If `true`, also record the output of the command in
+    /// `Output::tagged_output` in the order it was received. This does
+    /// nothing if `capture` is `false` or if `combine_output` is
+    /// `true`. The default is `false`.
+    ///
+    /// The order is determined by when this process reads the data,
+    /// so writes that happen very close together may be reordered.
+    pub capture_tagged: bool,
+
+    /// If `true`, output is also written to the stdout or stderr of
+    /// this process as it is captured. If `combine_output` is `true`,
+    /// the combined output is written to stdout. This does nothing if
+    /// `capture` is `false`. The default is `false`.
+    pub tee_output: bool,
+
+    /// If set, only the last `n` bytes of stdout and stderr are kept
+    /// in `Output`. This limits memory use for commands that produce
+    /// a lot of output. `Output::tagged_output` is not limited. This
+    /// does nothing if `capture` is `false`. The default is `None`.
+    ///
+    /// With `Command::run_async`, the output is only shortened
+    /// after the command has finished.
+    pub tail_output_bytes: Option<usize>,
+
+    /// If `true`, `\r\n` is replaced with `\n` in the captured stdout
+    /// and stderr. This can be used to get the same output on all
+    /// platforms. `Output::tagged_output` is not modified. The default
+    /// is `false`.
+    pub normalize_output_newlines: bool,
+
+    /// If set, the command is killed and an `OutputLimit` error is
+    /// returned once the captured stdout and stderr contain more than
+    /// this many lines in total. This does nothing if `capture` is
+    /// `false`. The error's `output` contains the output read so far.
+    /// This is not supported by `Command::run_async`. The default is
+    /// `None`.
+    pub max_output_lines: Option<usize>,
+
+    /// If set, this is called with each line the command writes to
+    /// stdout as soon as the line is available. This works whether or
+    /// not `capture` is `true`. If `combine_output` is `true`, this
+    /// receives both stdout and stderr. The default is `None`.
+    pub on_stdout_line: Option<LineCallback>,
+
+    /// If set, this is called with each line the command writes to
+    /// stderr as soon as the line is available. This works whether or
+    /// not `capture` is `true`. The default is `None`.
+    pub on_stderr_line: Option<LineCallback>,
+
+    /// If set, this is called with the [`std::process::Command`] right
+    /// before it is spawned. This can be used to set options that are
+    /// not otherwise supported by this type. The default is `None`.
+    pub std_command_fn: Option<StdCommandCallback>,
+
+    /// If set, this is called each time the command is about to be
+    /// run, after the command is logged. The default is `None`.
+    pub before_run: Option<CommandCallback>,
+
+    /// If set, this is called with the result of [`Command::run`]
+    /// right before it returns, whether the command succeeded or
+    /// not. The default is `None`.
+    pub after_run: Option<ResultCallback>,
+
+    /// If `false` (the default), inherit environment variables from the
+    /// current process. Variables in `env` are set in the child
+    /// process regardless of this setting.
+    pub clear_env: bool,
+
+    /// Environment variables to copy from the current process when
+    /// `clear_env` is `true`. Variables that are not set in the
+    /// current process are skipped. These are applied before `env`.
+    /// The default is empty.
+    pub env_inherit: Vec<OsString>,
+
+    /// Add or update environment variables in the child process.
+    pub env: HashMap<OsString, OsString>,
+
+    /// Add or update environment variables in the child process, in
+    /// order. This is applied after `env`, so a variable set in both
+    /// takes its value from here, and if a variable appears more than
+    /// once the last value is used. Unlike `env`, the order is kept in
+    /// [`Command::command_line_lossy_with_env`]. The default is empty.
+    pub env_ordered: Vec<(OsString, OsString)>,
+
+    /// Remove environment variables from the child process. This is
+    /// applied after `env` and `env_ordered`.
+    pub env_remove: Vec<OsString>,
+
+    /// If set, use this as the `PATH` of the child process. This is
+    /// applied after `env` and `env_ordered`, and before
+    /// `env_remove`. The default is `None`.
+    ///
+    /// On Unix, the program is looked up in this `PATH` if it is a
+    /// bare file name. On other platforms the parent's `PATH` may be
+    /// used to find the program instead.
+    pub path_override: Option<OsString>,
+
+    /// If `true`, run the command in a new process group. When the
+    /// command is killed (for example by [`Child::kill`] or due to
+    /// `timeout`), the whole process group is killed, including any
+    /// processes the command started. This only has an effect on
+    /// Unix. The default is `false`.
+    pub new_process_group: bool,
+
+    /// If set, the scheduling priority ("nice value") of the command.
+    /// Higher values mean lower priority. If the priority cannot be
+    /// set, running the command fails. This only has an effect on
+    /// Unix. The default is `None`.
+    pub nice: Option<i32>,
+
+    /// If `true`, file descriptors other than stdin, stdout, and
+    /// stderr are not inherited by the command, even if they were
+    /// opened without the close-on-exec flag. This only has an effect
+    /// on Unix. The default is `false`.
+    ///
+    /// On Linux this uses `close_range` where available. Otherwise
+    /// each possible descriptor is checked, up to the open file limit
+    /// but at most 65536, so descriptors above that are still
+    /// inherited.
+    pub close_fds: bool,
+
+    /// If set, run the command as this user ID. The default is `None`.
+    #[cfg(unix)]
+    pub uid: Option<u32>,
+
+    /// If set, run the command as this group ID. The default is
+    /// `None`.
+    #[cfg(unix)]
+    pub gid: Option<u32>,
+
+    /// If `true`, connect the command's stdout and stderr to a new
+    /// pseudo-terminal, and capture what is written to the terminal
+    /// in `Output::stdout`. This is useful for programs that change
+    /// their output when it is not a terminal. Note that the terminal
+    /// converts `\n` to `\r\n`. This takes precedence over the other
+    /// stdout and stderr options, and is not supported by
+    /// `Command::run_async`. The default is `false`.
+    #[cfg(all(unix, feature = "pty"))]
+    pub use_pty: bool,
+
+    /// If `true`, kill the child process when a [`Child`] returned by
+    /// [`Command::spawn`] is dropped without being waited on. The
+    /// default is `false`.
+    pub kill_on_drop: bool,
+
+    /// If set, kill the command if it has not finished after this
+    /// much time and return a `Timeout` error. The default is `None`.
+    pub timeout: Option<Duration>,
+
+    /// If set, kill the command if it has not finished by this time
+    /// and return a `Timeout` error. If `timeout` is also set, the
+    /// command is killed at whichever is sooner. The default is
+    /// `None`.
+    pub deadline: Option<Instant>,
+
+    /// If set, log a message each time this much time passes while
+    /// the command is running, to show that it has not stalled. The
+    /// message is logged according to `log_to`, at the same level as
+    /// the command line. An interval of zero is treated the same as
+    /// `None`. This is not supported by `Command::run_async`. The
+    /// default is `None`.
+    pub heartbeat_interval: Option<Duration>,
+
+    /// If set, a command that times out is first sent `SIGTERM`, and
+    /// is only killed if it is still running after this much time.
+    /// This gives the command a chance to exit cleanly. This only has
+    /// an effect on Unix, and is not supported by
+    /// `Command::run_async`. The default is `None`.
+    pub kill_grace: Option<Duration>,
+}
+
+impl Command {
+    /// Make a new `Command` with the given program.
+    ///
+    /// All other fields are set to the defaults.
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
+        Self {
+            program: program.as_ref().into(),
+            ..Default::default()
+        }
+    }
+
+    /// Make a new `Command` with the given program and args.
+    ///
+    /// All other fields are set to the defaults.
+    pub fn with_args<I, S1, S2>(program: S1, args: I) -> Self
+    where
+        S1: AsRef<OsStr>,
+        S2: AsRef<OsStr>,
+        I: IntoIterator<Item = S2>,
+    {
+        Self {
+            program: program.as_ref().into(),
+            args: args.into_iter().map(|arg| arg.as_ref().into()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Create a `Command` from a [`std::process::Command`].
+    ///
+    /// The program, arguments, directory, and environment variables
+    /// are copied. All other fields are set to the defaults.
+    ///
+    /// Note that [`std::process::Command`] does not provide a way to
+    /// check if [`std::process::Command::env_clear`] was called, so
+    /// `clear_env` is always `false`.
+    pub fn from_std(cmd: process::Command) -> Self {
+        let mut out = Self::with_args(cmd.get_program(), cmd.get_args());
+        out.dir = cmd.get_current_dir().map(Into::into);
+        for (key, value) in cmd.get_envs() {
+            if let Some(value) = value {
+                out.set_env(key, value);
+            } else {
+                out.env_remove(key);
+            }
+        }
+        out
+    }
+
+    /// Create a `Command` from a whitespace-separated string. If the
+    /// string is empty or all whitespace, `None` is returned.
+    ///
+    /// This function does not do unquoting or escaping.
+    pub fn from_whitespace_separated_str(s: &str) -> Option<Self> {
+        Self::from_argv(s.split_whitespace())
+    }
+
+    /// Create a `Command` from an argument vector. The first element
+    /// is the program and the rest are the arguments. If `argv` is
+    /// empty, `None` is returned.
+    pub fn from_argv<I, S>(argv: I) -> Option<Self>
+    where
+        S: AsRef<OsStr>,
+        I: IntoIterator<Item = S>,
+    {
+        let mut argv = argv.into_iter();
+        let program = argv.next()?;
+        Some(Self::with_args(program, argv))
+    }
+
+    /// Create a `Command` from a string using shell-like syntax. The
+    /// string is split into words with the [`shell_words`] crate, so
+    /// quotes and backslash escapes are handled. The first word is the
+    /// program and the rest are the arguments.
+    ///
+    /// This does not do any other shell processing, such as expanding
+    /// variables or globs.
+    #[cfg(feature = "shell-words")]
+    pub fn parse(s: &str) -> Result<Self, ParseError> {
+        let words = shell_words::split(s)
+            .map_err(|_| ParseError::MissingClosingQuote)?;
+        let mut words = words.into_iter();
+        let program = words.next().ok_or(ParseError::Empty)?;
+        Ok(Self::with_args(program, words))
+    }
+
+    /// Create a copy of this `Command` that runs `program` with no
+    /// arguments. All other fields are copied, so this can be used to
+    /// apply shared options to several programs.
+    pub fn with_program<S: AsRef<OsStr>>(&self, program: S) -> Self {
+        let mut cmd = self.clone();
+        cmd.program = program.as_ref().into();
+        cmd.args.clear();
+        cmd
+    }
+
+    /// Append a single argument.
+    pub fn add_arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.args.push(arg.as_ref().into());
+        self
+    }
+
+    /// Append a single argument from raw bytes. The bytes do not
+    /// need to be valid UTF-8.
+    #[cfg(unix)]
+    pub fn add_arg_bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        use std::os::unix::ffi::OsStrExt;
+
+        self.add_arg(OsStr::from_bytes(bytes))
+    }
+
+    /// Append two arguments.
+    ///
+    /// This is equivalent to calling `add_arg` twice; it is for the
+    /// common case where the arguments have different types, e.g. a
+    /// literal string for the first argument and a `Path` for the
+    /// second argument.
+    pub fn add_arg_pair<S1, S2>(&mut self, arg1: S1, arg2: S2) -> &mut Self
+    where
+        S1: AsRef<OsStr>,
+        S2: AsRef<OsStr>,
+    {
+        self.add_arg(arg1);
+        self.add_arg(arg2);
+        self
+    }
+
+    /// Append a flag followed by its value as two arguments, for
+    /// example `--name value`.
+    pub fn add_flag_value<S1, S2>(&mut self, flag: S1, value: S2) -> &mut Self
+    where
+        S1: AsRef<OsStr>,
+        S2: AsRef<OsStr>,
+    {
+        self.add_arg_pair(flag, value)
+    }
+
+    /// Append a flag and its value as a single argument joined with
+    /// `=`, for example `--name=value`.
+    pub fn add_flag_value_eq<S1, S2>(
+        &mut self,
+        flag: S1,
+        value: S2,
+    ) -> &mut Self
+    where
+        S1: AsRef<OsStr>,
+        S2: AsRef<OsStr>,
+    {
+        let mut arg = flag.as_ref().to_os_string();
+        arg.push("=");
+        arg.push(value);
+        self.add_arg(arg)
+    }
+
+    /// Append multiple arguments.
+    pub fn add_args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        S: AsRef<OsStr>,
+        I: IntoIterator<Item = S>,
+    {
+        for arg in args {
+            self.add_arg(arg);
+        }
+        self
+    }
+
+    /// Replace all arguments.
+    pub fn set_args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        S: AsRef<OsStr>,
+        I: IntoIterator<Item = S>,
+    {
+        self.args.clear();
+        self.add_args(args)
+    }
+
+    /// Append a single argument if `cond` is `true`.
+    pub fn add_arg_if<S: AsRef<OsStr>>(
+        &mut self,
+        cond: bool,
+        arg: S,
+    ) -> &mut Self {
+        if cond {
+            self.add_arg(arg);
+        }
+        self
+    }
+
+    /// Append multiple arguments if `cond` is `true`.
+    pub fn add_args_if<I, S>(&mut self, cond: bool, args: I) -> &mut Self
+    where
+        S: AsRef<OsStr>,
+        I: IntoIterator<Item = S>,
+    {
+        if cond {
+            self.add_args(args);
+        }
+        self
+    }
+
+    /// Set `capture` to `true`.
+    pub fn enable_capture(&mut self) -> &mut Self {
+        self.capture = true;
+        self
+    }
+
+    /// Set `combine_output` to `true`.
+    pub fn combine_output(&mut self) -> &mut Self {
+        self.combine_output = true;
+        self
+    }
+
+    /// Set `stdin` to `StdinSource::Inherit`.
+    ///
+    /// See the `stdin` field for how this interacts with `capture`.
+    pub fn inherit_stdin(&mut self) -> &mut Self {
+        self.stdin = StdinSource::Inherit;
+        self
+    }
+
+    /// Set `stdin` to `StdinSource::Null`.
+    pub fn null_stdin(&mut self) -> &mut Self {
+        self.stdin = StdinSource::Null;
+        self
+    }
+
+    /// Write the command's stdout to a file.
+    pub fn set_stdout_file<S: AsRef<OsStr>>(&mut self, path: S) -> &mut Self {
+        self.stdout_file = Some(path.as_ref().into());
+        self
+    }
+
+    /// Write the command's stderr to a file.
+    pub fn set_stderr_file<S: AsRef<OsStr>>(&mut self, path: S) -> &mut Self {
+        self.stderr_file = Some(path.as_ref().into());
+        self
+    }
+
+    /// Set a callback that receives each line written to stdout.
+    pub fn set_on_stdout_line<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.on_stdout_line = Some(Arc::new(f));
+        self
+    }
+
+    /// Set a callback that receives each line written to stderr.
+    pub fn set_on_stderr_line<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.on_stderr_line = Some(Arc::new(f));
+        self
+    }
+
+    /// Set a callback that is called with the command instead of
+    /// running it in `dry_run` mode.
+    pub fn set_dry_run_sink<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&Command) + Send + Sync + 'static,
+    {
+        self.dry_run_sink = Some(Arc::new(f));
+        self
+    }
+
+    /// Set a callback that modifies the [`std::process::Command`]
+    /// right before it is spawned.
+    pub fn with_std_command<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&mut process::Command) + Send + Sync + 'static,
+    {
+        self.std_command_fn = Some(Arc::new(f));
+        self
+    }
+
+    /// Set `clear_env` to `true`.
+    pub fn enable_clear_env(&mut self) -> &mut Self {
+        self.clear_env = true;
+        self
+    }
+
+    /// Set the program to run.
+    pub fn set_program<S: AsRef<OsStr>>(&mut self, program: S) -> &mut Self {
+        self.program = program.as_ref().into();
+        self
+    }
+
+    /// Run the command with `shell` instead of running the program
+    /// directly. See the `via_shell` field.
+    pub fn set_via_shell<S: AsRef<OsStr>>(&mut self, shell: S) -> &mut Self {
+        self.via_shell = Some(shell.as_ref().into());
         self
     }
 
@@ -310,118 +1795,1328 @@ impl Command {
         self
     }
 
-    /// Run the command.
-    ///
-    /// If `capture` is `true`, the command's output (stdout and
-    /// stderr) is returned along with the status. If not, the stdout
-    /// and stderr are empty.
-    ///
-    /// If the command fails to start an error is returned. If check
-    /// is set, an error is also returned if the command exits
-    /// non-zero or due to a signal.
-    ///
-    /// If `log_command` is `true` then the command line is logged
-    /// before running it. If the command fails the error is not
-    /// logged or printed, but the resulting error type implements
-    /// `Display` and can be used for this purpose.
-    pub fn run(&self) -> Result<Output, Error> {
-        let cmd_str = self.command_line_lossy();
+    /// Disable all logging by setting `log_command`,
+    /// `log_output_on_error`, and `log_output_always` to `false`.
+    pub fn quiet(&mut self) -> &mut Self {
+        self.log_command = false;
+        self.log_output_on_error = false;
+        self.log_output_always = false;
+        self
+    }
+
+    /// Add or update an environment variable in the child process.
+    pub fn set_env<K, V>(&mut self, key: K, value: V) -> &mut Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.env.insert(key.as_ref().into(), value.as_ref().into());
+        self
+    }
+
+    /// Add or update multiple environment variables in the child
+    /// process.
+    pub fn set_envs<I, K, V>(&mut self, vars: I) -> &mut Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        for (key, value) in vars {
+            self.set_env(key, value);
+        }
+        self
+    }
+
+    /// Copy all environment variables whose name starts with `prefix`
+    /// from the current process into `env`. The variables are read
+    /// when this method is called, not when the command is run.
+    pub fn inherit_env_prefix<S: AsRef<str>>(
+        &mut self,
+        prefix: S,
+    ) -> &mut Self {
+        let prefix = prefix.as_ref();
+        for (key, value) in std::env::vars_os() {
+            if key.to_str().is_some_and(|key| key.starts_with(prefix)) {
+                self.env.insert(key, value);
+            }
+        }
+        self
+    }
+
+    /// Append an environment variable to `env_ordered`.
+    pub fn add_env_ordered<K, V>(&mut self, key: K, value: V) -> &mut Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.env_ordered
+            .push((key.as_ref().into(), value.as_ref().into()));
+        self
+    }
+
+    /// Remove an environment variable from the child process.
+    pub fn env_remove<S: AsRef<OsStr>>(&mut self, key: S) -> &mut Self {
+        self.env_remove.push(key.as_ref().into());
+        self
+    }
+
+    /// Set the `PATH` of the child process. See `path_override`.
+    pub fn set_path<S: AsRef<OsStr>>(&mut self, path: S) -> &mut Self {
+        self.path_override = Some(path.as_ref().into());
+        self
+    }
+
+    /// Set the exit codes that are treated as success by `check`.
+    pub fn set_success_codes<I: IntoIterator<Item = i32>>(
+        &mut self,
+        codes: I,
+    ) -> &mut Self {
+        self.success_codes = Some(codes.into_iter().collect());
+        self
+    }
+
+    /// Set a callback that decides whether an exit status is treated
+    /// as success by `check`.
+    pub fn set_success_predicate<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&process::ExitStatus) -> bool + Send + Sync + 'static,
+    {
+        self.success_predicate = Some(Arc::new(f));
+        self
+    }
+
+    /// Set the number of times to run the command again if it fails.
+    pub fn set_retries(&mut self, retries: u32) -> &mut Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Set the maximum amount of time the command is allowed to run.
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Append a single argument, taking and returning `self` by
+    /// value.
+    ///
+    /// This is the by-value version of [`Command::add_arg`]. The other
+    /// by-value methods are [`Command::args`], [`Command::capture`],
+    /// [`Command::dir`], [`Command::env`], and [`Command::envs`].
+    pub fn arg<S: AsRef<OsStr>>(mut self, arg: S) -> Self {
+        self.add_arg(arg);
+        self
+    }
+
+    /// Append multiple arguments, taking and returning `self` by
+    /// value.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        S: AsRef<OsStr>,
+        I: IntoIterator<Item = S>,
+    {
+        self.add_args(args);
+        self
+    }
+
+    /// Set `capture` to `true`, taking and returning `self` by value.
+    pub fn capture(mut self) -> Self {
+        self.enable_capture();
+        self
+    }
+
+    /// Set the directory from which to run the program, taking and
+    /// returning `self` by value.
+    pub fn dir<S: AsRef<OsStr>>(mut self, dir: S) -> Self {
+        self.set_dir(dir);
+        self
+    }
+
+    /// Add or update an environment variable in the child process,
+    /// taking and returning `self` by value.
+    pub fn env<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.set_env(key, value);
+        self
+    }
+
+    /// Add or update multiple environment variables in the child
+    /// process, taking and returning `self` by value.
+    pub fn envs<I, K, V>(mut self, vars: I) -> Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        self.set_envs(vars);
+        self
+    }
+
+    /// Run the command.
+    ///
+    /// If `capture` is `true`, the command's output (stdout and
+    /// stderr) is returned along with the status. If not, the stdout
+    /// and stderr are empty.
+    ///
+    /// If the command fails to start an error is returned. If check
+    /// is set, an error is also returned if the command exits
+    /// non-zero or due to a signal. If `timeout` is set and the
+    /// command does not finish in time, it is killed and an error is
+    /// returned.
+    ///
+    /// If `retries` is non-zero and `check` is set, the command is run
+    /// again if it fails, up to `retries` more times. The error from
+    /// the last attempt is returned if all attempts fail.
+    ///
+    /// If `log_command` is `true` then the command line is logged
+    /// before running it. If the command fails the error is not
+    /// logged or printed, but the resulting error type implements
+    /// `Display` and can be used for this purpose.
+    pub fn run(&self) -> Result<Output, Error> {
+        #[cfg(feature = "tracing")]
+        let span = matches!(self.log_to, LogTo::Tracing).then(|| {
+            tracing::info_span!("command", command = %self.command_line_lossy())
+        });
+        #[cfg(feature = "tracing")]
+        let _enter = span.as_ref().map(|span| span.enter());
+
+        let result = self.run_with_retries();
+        self.after_run(&result);
+        result
+    }
+
+    /// Run the command and discard the output. This is the same as
+    /// [`Command::run`], but makes it clear that only success or
+    /// failure matters. Note that failure is only detected if `check`
+    /// is `true`.
+    pub fn run_check(&self) -> Result<(), Error> {
+        self.run().map(|_| ())
+    }
+
+    /// Run the command. This is the same as [`Command::run`], except
+    /// that if no output is captured only the exit status is
+    /// returned.
+    pub fn run_typed(&self) -> Result<RunResult, Error> {
+        let output = self.run()?;
+        if self.captures_output() {
+            Ok(RunResult::Captured(output))
+        } else {
+            Ok(RunResult::Status(output.status))
+        }
+    }
+
+    /// Run the command with capture enabled, regardless of the
+    /// `capture` field.
+    ///
+    /// `self` is not modified; capture is enabled on a copy of the
+    /// command.
+    pub fn run_capture(&self) -> Result<Output, Error> {
+        let mut cmd = self.clone();
+        cmd.capture = true;
+        cmd.run()
+    }
+
+    /// Run the command with capture enabled and `check` disabled,
+    /// and return the output along with whether the command exited
+    /// successfully, as given by [`process::ExitStatus::success`].
+    ///
+    /// An error is only returned if the command could not be run. If
+    /// the command is killed due to a timeout or output limit, the
+    /// output produced before it was killed is returned along with
+    /// `false`.
+    ///
+    /// `self` is not modified; the changes are made on a copy of the
+    /// command.
+    pub fn run_allow_failure(&self) -> Result<(Output, bool), Error> {
+        let mut cmd = self.clone();
+        cmd.capture = true;
+        cmd.check = false;
+        match cmd.run() {
+            Ok(output) => {
+                let success = output.status.success();
+                Ok((output, success))
+            }
+            Err(Error {
+                output: Some(output),
+                kind: ErrorKind::Timeout(_) | ErrorKind::OutputLimit(_),
+                ..
+            }) => Ok((output, false)),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Write `input` to the command's stdin, enable capture, and run
+    /// the command.
+    ///
+    /// This sets `stdin` to [`StdinSource::Bytes`] and `capture` to
+    /// `true` on `self` before running.
+    pub fn run_with_input<B: Into<Vec<u8>>>(
+        &mut self,
+        input: B,
+    ) -> Result<Output, Error> {
+        self.stdin = StdinSource::Bytes(input.into());
+        self.capture = true;
+        self.run()
+    }
+
+    /// Run the command with capture enabled and return its stdout as
+    /// a string. The output is converted lossily and leading and
+    /// trailing whitespace is removed.
+    ///
+    /// `self` is not modified; capture is enabled on a copy of the
+    /// command.
+    pub fn run_get_string(&self) -> Result<String, Error> {
+        let output = self.run_capture()?;
+        Ok(output.stdout_string_lossy().trim().to_string())
+    }
+
+    /// Run the command with capture enabled and parse its stdout as
+    /// JSON.
+    ///
+    /// `self` is not modified; capture is enabled on a copy of the
+    /// command. If the output cannot be parsed, an error with kind
+    /// `Parse` is returned.
+    #[cfg(feature = "serde_json")]
+    pub fn run_json<T: serde::de::DeserializeOwned>(&self) -> Result<T, Error> {
+        let output = self.run_capture()?;
+        serde_json::from_slice(&output.stdout).map_err(|err| Error {
+            command: self.clone(),
+            kind: ErrorKind::Parse(err),
+            output: Some(output),
+            resolved_program: None,
+        })
+    }
+
+    /// Run the command, retrying if configured to do so.
+    fn run_with_retries(&self) -> Result<Output, Error> {
+        if self.dry_run {
+            return Ok(self.dry_run_output());
+        }
+
+        let mut attempt = 0;
+        loop {
+            let result = self.spawn().and_then(Child::wait);
+            match &result {
+                Err(err) if self.should_retry(err, attempt) => {
+                    if let Some(delay) = self.retry_delay_after(attempt) {
+                        thread::sleep(delay);
+                    }
+                    attempt += 1;
+                }
+                _ => return result,
+            }
+        }
+    }
+
+    /// Get the time to wait before the next attempt, where `attempt`
+    /// is zero after the first failure.
+    fn retry_delay_after(&self, attempt: u32) -> Option<Duration> {
+        let Some((initial, factor)) = self.retry_backoff else {
+            return self.retry_delay;
+        };
+        let secs = initial.as_secs_f64()
+            * factor.powi(attempt.try_into().unwrap_or(i32::MAX));
+        if secs.is_nan() || secs < 0.0 {
+            return Some(Duration::ZERO);
+        }
+        // Use the maximum if the delay is too large to represent.
+        let max = self.retry_backoff_max.unwrap_or(Duration::MAX);
+        Some(Duration::try_from_secs_f64(secs).unwrap_or(max).min(max))
+    }
+
+    /// Call the `after_run` callback, if set.
+    fn after_run(&self, result: &Result<Output, Error>) {
+        if let Some(after_run) = &self.after_run {
+            after_run(self, result);
+        }
+    }
+
+    /// Log the command without running it, and return a successful
+    /// output.
+    fn dry_run_output(&self) -> Output {
+        if self.log_command {
+            self.log(LogLevel::Command, &self.command_line_lossy());
+        }
+
+        if let Some(sink) = &self.dry_run_sink {
+            sink(self);
+        }
+
+        #[cfg(unix)]
+        let status = {
+            use std::os::unix::process::ExitStatusExt;
+            process::ExitStatus::from_raw(0)
+        };
+        #[cfg(windows)]
+        let status = {
+            use std::os::windows::process::ExitStatusExt;
+            process::ExitStatus::from_raw(0)
+        };
+
+        Output {
+            status,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            tagged_output: Vec::new(),
+            duration: Duration::ZERO,
+            command: Some(self.clone()),
+        }
+    }
+
+    /// Check if the command should be run again after failing with
+    /// `err` on attempt number `attempt` (starting from zero).
+    fn should_retry(&self, err: &Error, attempt: u32) -> bool {
+        if !self.check || attempt >= self.retries {
+            return false;
+        }
+        match err.kind {
+            ErrorKind::Run(_) | ErrorKind::Exit(_) | ErrorKind::Signal(_) => {
+                true
+            }
+            ErrorKind::Timeout(_) => self.retry_on_timeout,
+            ErrorKind::OutputLimit(_) => false,
+            #[cfg(feature = "serde_json")]
+            ErrorKind::Parse(_) => false,
+        }
+    }
+
+    /// Start running the command without waiting for it to finish.
+    ///
+    /// If `log_command` is `true` then the command line is logged
+    /// before starting it. Call [`Child::wait`] to wait for the
+    /// command to finish; the other options such as `check` and
+    /// `timeout` are applied there in the same way as in
+    /// [`Command::run`].
+    pub fn spawn(&self) -> Result<Child, Error> {
+        self.spawn_stage(None, None)
+    }
+
+    /// Spawn the command as part of a [`Pipeline`].
+    ///
+    /// If set, `stdin` and `stdout` override the command's stdin and
+    /// stdout.
+    fn spawn_stage(
+        &self,
+        stdin: Option<os_pipe::PipeReader>,
+        stdout: Option<os_pipe::PipeWriter>,
+    ) -> Result<Child, Error> {
+        if self.dry_run {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot spawn a command in dry-run mode",
+            ))
+            .into_run_error(self);
+        }
+
+        self.before_spawn()?;
+
+        let mut cmd: process::Command = self.into();
+        let combined_reader = self.configure_stdio(&mut cmd)?;
+        if combined_reader.is_none() {
+            // Line callbacks need a pipe even if the output is not
+            // captured.
+            if self.on_stdout_line.is_some()
+                && self.stream_config(Stream::Stdout) == StreamConfig::Inherit
+            {
+                cmd.stdout(process::Stdio::piped());
+            }
+            if self.on_stderr_line.is_some()
+                && self.stream_config(Stream::Stderr) == StreamConfig::Inherit
+            {
+                cmd.stderr(process::Stdio::piped());
+            }
+        }
+        #[cfg(all(unix, feature = "pty"))]
+        let pty_reader = if self.use_pty {
+            let (reader, terminal) = open_pty().into_run_error(self)?;
+            cmd.stdout(terminal.try_clone().into_run_error(self)?);
+            cmd.stderr(terminal);
+            Some(reader)
+        } else {
+            None
+        };
+        if let Some(stdin) = stdin {
+            cmd.stdin(stdin);
+        }
+        if let Some(stdout) = stdout {
+            cmd.stdout(stdout);
+        }
+        if let Some(f) = &self.std_command_fn {
+            f(&mut cmd);
+        }
+
+        let start = Instant::now();
+        let mut child = cmd.spawn().into_run_error(self)?;
+
+        // Drop the command so that the pipe ends passed to the child
+        // are closed in this process.
+        drop(cmd);
+
+        let stdin_writer = match (&self.stdin, child.stdin.take()) {
+            (StdinSource::Bytes(bytes), Some(mut child_stdin)) => {
+                let bytes = bytes.clone();
+                Some(thread::spawn(move || child_stdin.write_all(&bytes)))
+            }
+            _ => None,
+        };
+
+        let tagged = if self.captures_output() && self.capture_tagged {
+            Some(TaggedChunks::default())
+        } else {
+            None
+        };
+        let output_lines = self
+            .max_output_lines
+            .filter(|_| self.captures_output())
+            .map(|_| Arc::new(AtomicUsize::new(0)));
+        let reader_options = |stream| {
+            let keep = self.stream_config(stream) == StreamConfig::Capture;
+            ReaderOptions {
+                keep,
+                on_line: match stream {
+                    Stream::Stdout => self.on_stdout_line.clone(),
+                    Stream::Stderr => self.on_stderr_line.clone(),
+                },
+                tagged: tagged
+                    .clone()
+                    .filter(|_| keep)
+                    .map(|tagged| (stream, tagged)),
+                tee: (keep && self.tee_output).then_some(stream),
+                #[cfg(feature = "time")]
+                tee_timestamps: keep && self.tee_output && self.log_timestamps,
+                tail: self.tail_output_bytes,
+                line_count: output_lines.clone(),
+            }
+        };
+        #[cfg(all(unix, feature = "pty"))]
+        if let Some(reader) = pty_reader {
+            let opts = ReaderOptions {
+                keep: true,
+                ..reader_options(Stream::Stdout)
+            };
+            return Ok(Child {
+                command: self.clone(),
+                inner: child,
+                start,
+                stdin_writer,
+                stdout_reader: Some(read_in_thread(reader, opts)),
+                stderr_reader: None,
+                tagged,
+                output_lines,
+            });
+        }
+        let stdout_reader = if let Some(reader) = combined_reader {
+            Some(read_in_thread(reader, reader_options(Stream::Stdout)))
+        } else {
+            child.stdout.take().map(|reader| {
+                read_in_thread(reader, reader_options(Stream::Stdout))
+            })
+        };
+        let stderr_reader = child.stderr.take().map(|reader| {
+            read_in_thread(reader, reader_options(Stream::Stderr))
+        });
+
+        Ok(Child {
+            command: self.clone(),
+            inner: child,
+            start,
+            stdin_writer,
+            stdout_reader,
+            stderr_reader,
+            tagged,
+            output_lines,
+        })
+    }
+
+    /// Create a [`Pipeline`] that sends the stdout of this command to
+    /// the stdin of `next`.
+    pub fn pipe(self, next: Command) -> Pipeline {
+        Pipeline {
+            commands: vec![self, next],
+        }
+    }
+
+    /// Run the command asynchronously.
+    ///
+    /// This is the same as [`Command::run`], except that the command
+    /// is run with [`tokio::process::Command`]. The `capture_tagged`,
+    /// `tee_output`, `use_pty`, `heartbeat_interval`,
+    /// `max_output_lines`, `kill_grace`, `on_stdout_line`, and
+    /// `on_stderr_line` options are not supported and are ignored.
+    #[cfg(feature = "tokio")]
+    pub async fn run_async(&self) -> Result<Output, Error> {
+        let result = self.run_async_with_retries().await;
+        self.after_run(&result);
+        result
+    }
+
+    /// Run the command asynchronously, retrying if configured to do
+    /// so.
+    #[cfg(feature = "tokio")]
+    async fn run_async_with_retries(&self) -> Result<Output, Error> {
+        if self.dry_run {
+            return Ok(self.dry_run_output());
+        }
+
+        let mut attempt = 0;
+        loop {
+            let result = self.run_async_once().await;
+            match &result {
+                Err(err) if self.should_retry(err, attempt) => {
+                    if let Some(delay) = self.retry_delay_after(attempt) {
+                        tokio::time::sleep(delay).await;
+                    }
+                    attempt += 1;
+                }
+                _ => return result,
+            }
+        }
+    }
+
+    /// Run the command asynchronously without retrying.
+    #[cfg(feature = "tokio")]
+    async fn run_async_once(&self) -> Result<Output, Error> {
+        self.before_spawn()?;
+
+        let mut std_cmd = process::Command::from(self);
+        let combined_reader = self.configure_stdio(&mut std_cmd)?;
+        let mut cmd = tokio::process::Command::from(std_cmd);
+        // If the timeout elapses the child is killed when it is
+        // dropped.
+        cmd.kill_on_drop(
+            self.kill_on_drop
+                || self.timeout.is_some()
+                || self.deadline.is_some(),
+        );
+        if let Some(f) = &self.std_command_fn {
+            f(cmd.as_std_mut());
+        }
+
+        let start = Instant::now();
+        let mut child = cmd.spawn().into_run_error(self)?;
+
+        // Drop the command so that the write ends of the combined
+        // output pipe are closed in this process.
+        drop(cmd);
+
+        let stdin_writer = match (&self.stdin, child.stdin.take()) {
+            (StdinSource::Bytes(bytes), Some(mut child_stdin)) => {
+                use tokio::io::AsyncWriteExt;
+
+                let bytes = bytes.clone();
+                Some(tokio::spawn(async move {
+                    child_stdin.write_all(&bytes).await
+                }))
+            }
+            _ => None,
+        };
+
+        let combined_reader = combined_reader.map(|mut reader| {
+            tokio::task::spawn_blocking(move || {
+                let mut output = Vec::new();
+                reader.read_to_end(&mut output)?;
+                Ok::<_, io::Error>(output)
+            })
+        });
+
+        #[cfg_attr(not(unix), allow(unused_variables))]
+        let pid = child.id();
+        let output = if let Some(timeout) = self.effective_timeout(start) {
+            // The wait future is pinned here rather than moved into
+            // `timeout` so that the child is not dropped (and possibly
+            // reaped) before its process group is killed.
+            let wait = child.wait_with_output();
+            tokio::pin!(wait);
+            match tokio::time::timeout(timeout, &mut wait).await {
+                Ok(output) => output,
+                Err(_) => {
+                    #[cfg(unix)]
+                    if let (true, Some(pid)) = (self.new_process_group, pid) {
+                        let _ = kill_process_group(pid);
+                    }
+                    return Err(Error {
+                        command: self.clone(),
+                        kind: ErrorKind::Timeout(start.elapsed()),
+                        output: None,
+                        resolved_program: None,
+                    });
+                }
+            }
+        } else {
+            child.wait_with_output().await
+        };
+        let mut out: Output = output.into_run_error(self)?.into();
+        out.duration = start.elapsed();
+        out.command = Some(self.clone());
+
+        if let Some(stdin_writer) = stdin_writer {
+            let result = stdin_writer.await.expect("writer task panicked");
+            ignore_broken_pipe(result).into_run_error(self)?;
+        }
+
+        if let Some(combined_reader) = combined_reader {
+            out.stdout = combined_reader
+                .await
+                .expect("reader task panicked")
+                .into_run_error(self)?;
+        }
+        if let Some(tail) = self.tail_output_bytes {
+            keep_tail(&mut out.stdout, tail);
+            keep_tail(&mut out.stderr, tail);
+        }
+        self.check_output(out)
+    }
+
+    /// Log the command and perform checks before the command is
+    /// spawned.
+    fn before_spawn(&self) -> Result<(), Error> {
         if self.log_command {
-            match self.log_to {
-                LogTo::Stdout => println!("{}", cmd_str),
+            self.log(LogLevel::Command, &self.command_line_lossy());
+        }
+
+        if let Some(before_run) = &self.before_run {
+            before_run(self);
+        }
 
-                #[cfg(feature = "logging")]
-                LogTo::Log => log::info!("{}", cmd_str),
+        if self.create_dir {
+            if let Some(dir) = &self.dir {
+                fs::create_dir_all(dir).into_run_error(self)?;
             }
         }
 
-        let mut cmd: process::Command = self.into();
-        let out = if self.capture {
-            if self.combine_output {
-                combine_output(cmd).into_run_error(self)?
-            } else {
-                cmd.output().into_run_error(self)?.into()
+        if self.verify_dir {
+            if let Some(dir) = &self.dir {
+                if !dir.is_dir() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("directory '{}' does not exist", dir.display()),
+                    ))
+                    .into_run_error(self);
+                }
             }
-        } else {
-            let status = cmd.status().into_run_error(self)?;
-            Output {
-                stdout: Vec::new(),
-                stderr: Vec::new(),
-                status,
+        }
+
+        if self.capture
+            && ((self.stdout_config.is_none() && self.stdout_file.is_some())
+                || (self.stderr_config.is_none() && self.stderr_file.is_some()))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot both capture output and redirect it to a file",
+            ))
+            .into_run_error(self);
+        }
+
+        if self.combine_output && self.stderr_to_parent {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot both combine output and send stderr to the parent",
+            ))
+            .into_run_error(self);
+        }
+
+        if self.expand_args
+            && self.expand_args_strict
+            && self.via_shell.is_none()
+        {
+            for arg in &self.args {
+                if let Err((_, name)) = self.expand_arg(arg) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("undefined variable '{name}' in arguments"),
+                    ))
+                    .into_run_error(self);
+                }
             }
+        }
+
+        Ok(())
+    }
+
+    /// Get the value of an environment variable as seen by the child
+    /// process.
+    fn child_env_var(&self, name: &OsStr) -> Option<OsString> {
+        if self.env_remove.iter().any(|key| key == name) {
+            None
+        } else if let (Some(path), true) = (&self.path_override, name == "PATH")
+        {
+            Some(path.clone())
+        } else if let Some((_, value)) =
+            self.env_ordered.iter().rev().find(|(key, _)| key == name)
+        {
+            Some(value.clone())
+        } else if let Some(value) = self.env.get(name) {
+            Some(value.clone())
+        } else if self.clear_env && !self.env_inherit.iter().any(|k| k == name)
+        {
+            None
+        } else {
+            std::env::var_os(name)
+        }
+    }
+
+    /// Replace `${NAME}` in `arg` with the value of `NAME`. On error,
+    /// the argument with undefined variables expanded to an empty
+    /// string is returned along with the name of the first undefined
+    /// variable. Arguments that are not valid UTF-8 are returned
+    /// unchanged.
+    fn expand_arg(&self, arg: &OsStr) -> Result<OsString, (OsString, String)> {
+        let Some(mut rest) = arg.to_str() else {
+            return Ok(arg.to_os_string());
         };
-        if self.check && !out.status.success() {
-            if self.capture && self.log_output_on_error {
-                let mut msg =
-                    format!("command '{}' failed: {}", cmd_str, out.status);
-                if self.combine_output {
-                    msg = format!(
-                        "{}\noutput:\n{}",
-                        msg,
-                        out.stdout_string_lossy()
-                    );
+        let mut out = OsString::new();
+        let mut undefined = None;
+        while let Some(start) = rest.find("${") {
+            let Some(len) = rest[start + 2..].find('}') else {
+                break;
+            };
+            let name = &rest[start + 2..start + 2 + len];
+            out.push(&rest[..start]);
+            match self.child_env_var(OsStr::new(name)) {
+                Some(value) => out.push(value),
+                None => {
+                    undefined.get_or_insert_with(|| name.to_string());
+                }
+            }
+            rest = &rest[start + 3 + len..];
+        }
+        out.push(rest);
+        match undefined {
+            Some(name) => Err((out, name)),
+            None => Ok(out),
+        }
+    }
+
+    /// Get the configuration for the command's stdin, or `None` to
+    /// leave the default.
+    fn stdin_stdio(&self) -> Result<Option<process::Stdio>, Error> {
+        if let Some(config) = &self.stdin_config {
+            return Ok(match config {
+                StreamConfig::Inherit => None,
+                StreamConfig::Null => Some(process::Stdio::null()),
+                StreamConfig::File(path) => {
+                    Some(fs::File::open(path).into_run_error(self)?.into())
+                }
+                StreamConfig::Capture | StreamConfig::Stdout => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "stdin cannot be captured or sent to stdout",
+                    ))
+                    .into_run_error(self);
+                }
+            });
+        }
+
+        Ok(match &self.stdin {
+            StdinSource::Inherit => {
+                if self.capture && !self.combine_output {
+                    // Match the behavior of `process::Command::output`.
+                    Some(process::Stdio::null())
                 } else {
-                    msg = format!(
-                        "{}\nstdout:\n{}\nstderr:\n{}",
-                        msg,
-                        out.stdout_string_lossy(),
-                        out.stderr_string_lossy()
-                    );
+                    None
                 }
-                match self.log_to {
-                    LogTo::Stdout => println!("{}", msg),
+            }
+            StdinSource::Null => Some(process::Stdio::null()),
+            StdinSource::Bytes(_) => Some(process::Stdio::piped()),
+            StdinSource::File(path) => {
+                Some(fs::File::open(path).into_run_error(self)?.into())
+            }
+        })
+    }
+
+    /// Get where `stream` goes. If `stdout_config` or `stderr_config`
+    /// is not set, this is determined by the `capture`,
+    /// `combine_output`, `stderr_to_parent`, and file fields.
+    fn stream_config(&self, stream: Stream) -> StreamConfig {
+        let (config, file) = match stream {
+            Stream::Stdout => (&self.stdout_config, &self.stdout_file),
+            Stream::Stderr => (&self.stderr_config, &self.stderr_file),
+        };
+        let config = if let Some(config) = config {
+            config.clone()
+        } else if let Some(file) = file {
+            StreamConfig::File(file.clone())
+        } else if self.capture
+            && !(stream == Stream::Stderr && self.stderr_to_parent)
+        {
+            StreamConfig::Capture
+        } else {
+            StreamConfig::Inherit
+        };
+        if stream == Stream::Stderr
+            && config == StreamConfig::Capture
+            && self.combine_output
+            && self.stream_config(Stream::Stdout) == StreamConfig::Capture
+        {
+            StreamConfig::Stdout
+        } else {
+            config
+        }
+    }
+
+    /// Check if stdout or stderr is captured.
+    fn captures_output(&self) -> bool {
+        self.stream_config(Stream::Stdout) == StreamConfig::Capture
+            || self.stream_config(Stream::Stderr) == StreamConfig::Capture
+    }
 
-                    #[cfg(feature = "logging")]
-                    LogTo::Log => log::error!("{}", msg),
+    /// Set up the stdin, stdout, and stderr of `cmd`. If stdout and
+    /// stderr are combined, the read end of the combined pipe is
+    /// returned.
+    fn configure_stdio(
+        &self,
+        cmd: &mut process::Command,
+    ) -> Result<Option<os_pipe::PipeReader>, Error> {
+        let stdout_config = self.stream_config(Stream::Stdout);
+        let stderr_config = self.stream_config(Stream::Stderr);
+        let mut combined_reader = None;
+        if stdout_config == StreamConfig::Stdout {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "stdout cannot be sent to stdout",
+            ))
+            .into_run_error(self);
+        } else if stderr_config == StreamConfig::Stdout {
+            let (stdout, stderr) = match &stdout_config {
+                StreamConfig::Capture => {
+                    let (reader, writer) =
+                        os_pipe::pipe().into_run_error(self)?;
+                    combined_reader = Some(reader);
+                    let writer_clone =
+                        writer.try_clone().into_run_error(self)?;
+                    (writer.into(), writer_clone.into())
+                }
+                StreamConfig::Inherit => (
+                    process::Stdio::inherit(),
+                    os_pipe::dup_stdout().into_run_error(self)?.into(),
+                ),
+                StreamConfig::Null => {
+                    (process::Stdio::null(), process::Stdio::null())
+                }
+                StreamConfig::File(path) => {
+                    let file =
+                        self.open_output_file(path, self.stdout_file_append)?;
+                    let file_clone = file.try_clone().into_run_error(self)?;
+                    (file.into(), file_clone.into())
+                }
+                StreamConfig::Stdout => {
+                    unreachable!("stdout config checked above")
                 }
+            };
+            cmd.stdout(stdout);
+            cmd.stderr(stderr);
+        } else {
+            if let Some(stdio) =
+                self.output_stdio(&stdout_config, self.stdout_file_append)?
+            {
+                cmd.stdout(stdio);
+            }
+            if let Some(stdio) =
+                self.output_stdio(&stderr_config, self.stderr_file_append)?
+            {
+                cmd.stderr(stdio);
+            }
+        }
+        if let Some(stdio) = self.stdin_stdio()? {
+            cmd.stdin(stdio);
+        }
+        Ok(combined_reader)
+    }
+
+    /// Get the configuration for an output stream, or `None` to leave
+    /// the default. If `config` is a file, it is opened for writing.
+    fn output_stdio(
+        &self,
+        config: &StreamConfig,
+        append: bool,
+    ) -> Result<Option<process::Stdio>, Error> {
+        Ok(match config {
+            StreamConfig::Inherit | StreamConfig::Stdout => None,
+            StreamConfig::Null => Some(process::Stdio::null()),
+            StreamConfig::Capture => Some(process::Stdio::piped()),
+            StreamConfig::File(path) => {
+                Some(self.open_output_file(path, append)?.into())
+            }
+        })
+    }
+
+    /// Open `path` for writing output to. The file is created if
+    /// needed, and truncated unless `append` is `true`.
+    fn open_output_file(
+        &self,
+        path: &Path,
+        append: bool,
+    ) -> Result<fs::File, Error> {
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .into_run_error(self)
+    }
+
+    /// Return an error if `check` is `true` and the command was not
+    /// successful. Otherwise return `out` unchanged.
+    fn check_output(&self, mut out: Output) -> Result<Output, Error> {
+        if self.normalize_output_newlines {
+            normalize_newlines(&mut out.stdout);
+            normalize_newlines(&mut out.stderr);
+        }
+
+        let failed = self.check && !self.is_success(&out.status);
+        if self.captures_output() {
+            if failed && (self.log_output_on_error || self.log_output_always) {
+                self.log_output(&out, LogLevel::Error);
+            } else if self.log_output_always {
+                self.log_output(&out, LogLevel::Output);
             }
+        }
 
+        if failed {
             return Err(Error {
                 command: self.clone(),
-                kind: ErrorKind::Exit(out.status),
+                kind: exit_error_kind(out.status),
+                output: self.captures_output().then_some(out),
+                resolved_program: None,
             });
         }
         Ok(out)
     }
 
+    /// Log a message according to `log_to`.
+    #[cfg_attr(not(feature = "logging"), allow(unused_variables))]
+    fn log(&self, level: LogLevel, msg: &str) {
+        #[cfg(feature = "time")]
+        let timestamped;
+        #[cfg(feature = "time")]
+        let msg = if self.log_timestamps
+            && matches!(self.log_to, LogTo::Stdout | LogTo::Writer(_))
+        {
+            timestamped = add_timestamps(msg);
+            timestamped.as_str()
+        } else {
+            msg
+        };
+
+        match &self.log_to {
+            LogTo::Stdout => println!("{}", msg),
+            LogTo::Writer(writer) => {
+                let _ = writeln!(writer.lock().unwrap(), "{}", msg);
+            }
+
+            #[cfg(feature = "logging")]
+            LogTo::Log => match level {
+                #[cfg(not(feature = "structured_logging"))]
+                LogLevel::Command => log::log!(self.log_level, "{}", msg),
+                #[cfg(feature = "structured_logging")]
+                LogLevel::Command => {
+                    let program = self.program.to_string_lossy();
+                    let dir = self
+                        .dir
+                        .as_ref()
+                        .map(|dir| dir.to_string_lossy())
+                        .unwrap_or_default();
+                    log::log!(
+                        self.log_level,
+                        program = &*program,
+                        args = self.args.len(),
+                        dir = &*dir;
+                        "{}",
+                        msg
+                    )
+                }
+                LogLevel::Output => log::info!("{}", msg),
+                LogLevel::Error => log::error!("{}", msg),
+            },
+
+            #[cfg(feature = "tracing")]
+            LogTo::Tracing => match level {
+                LogLevel::Command | LogLevel::Output => {
+                    tracing::info!("{}", msg)
+                }
+                LogLevel::Error => tracing::error!("{}", msg),
+            },
+        }
+    }
+
+    /// Log the output of a command. If `level` is `LogLevel::Error`
+    /// the command is described as having failed.
+    fn log_output(&self, out: &Output, level: LogLevel) {
+        let verb = match level {
+            LogLevel::Error => "failed",
+            _ => "finished",
+        };
+        let mut msg = format!(
+            "command '{}' {}: {}",
+            self.command_line_lossy(),
+            verb,
+            out.status
+        );
+        if self.stream_config(Stream::Stderr) == StreamConfig::Stdout {
+            msg = format!("{}\noutput:\n{}", msg, out.stdout_string_lossy());
+        } else {
+            msg = format!(
+                "{}\nstdout:\n{}\nstderr:\n{}",
+                msg,
+                out.stdout_string_lossy(),
+                out.stderr_string_lossy()
+            );
+        }
+        self.log(level, &msg);
+    }
+
+    /// Get how long the command can run for if it was started at
+    /// `start`, taking both `timeout` and `deadline` into account.
+    fn effective_timeout(&self, start: Instant) -> Option<Duration> {
+        let until_deadline = self
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(start));
+        match (self.timeout, until_deadline) {
+            (Some(timeout), Some(until_deadline)) => {
+                Some(timeout.min(until_deadline))
+            }
+            (timeout, until_deadline) => timeout.or(until_deadline),
+        }
+    }
+
+    /// Check if `status` should be treated as success.
+    fn is_success(&self, status: &process::ExitStatus) -> bool {
+        if let Some(predicate) = &self.success_predicate {
+            predicate(status)
+        } else if let Some(codes) = &self.success_codes {
+            status.code().is_some_and(|code| codes.contains(&code))
+        } else {
+            status.success()
+        }
+    }
+
+    /// Convert to a [`std::process::Command`]. This is the same as
+    /// the `From<&Command>` impl, and can be used to inspect how the
+    /// command would be run.
+    ///
+    /// Only the program, arguments, directory, environment, and
+    /// process attributes are set; stdio redirection and the other
+    /// options applied by [`Command::run`] are not.
+    pub fn to_std(&self) -> process::Command {
+        self.into()
+    }
+
+    /// Check if `self` and `other` run the same program with the
+    /// same arguments, directory, and environment. Unlike `==`, other
+    /// fields such as the logging settings are ignored.
+    pub fn same_invocation(&self, other: &Command) -> bool {
+        self.program == other.program
+            && self.args == other.args
+            && self.dir == other.dir
+            && self.env == other.env
+            && self.env_ordered == other.env_ordered
+            && self.clear_env == other.clear_env
+    }
+
+    /// Find the program that would be run.
+    ///
+    /// If `program` contains a path separator it is returned
+    /// unchanged. Otherwise each directory in the `PATH` is searched
+    /// for an executable file with that name. The `PATH` is taken from
+    /// `path_override` if set, then from `env_ordered` and `env`, and
+    /// finally from the current process (unless `clear_env` is `true`
+    /// and `PATH` is not in `env_inherit`).
+    ///
+    /// Returns `None` if the program is not found.
+    pub fn resolve_program(&self) -> Option<PathBuf> {
+        let program = Path::new(&self.program);
+        if program.parent() != Some(Path::new("")) {
+            return Some(program.into());
+        }
+
+        let path = self
+            .path_override
+            .clone()
+            .or_else(|| self.child_env_var(OsStr::new("PATH")))?;
+
+        std::env::split_paths(&path)
+            .find_map(|dir| executable_path(dir.join(program)))
+    }
+
     /// Format as a space-separated command line.
     ///
     /// The program path and the arguments are converted to strings
-    /// with [`String::from_utf8_lossy`].
+    /// with [`OsStr::to_string_lossy`].
     ///
     /// If any component contains characters that are not ASCII
-    /// alphanumeric or in the set `/-_,:.=+`, the component is
-    /// quoted with `'` (single quotes). This is both too aggressive
-    /// (unnecessarily quoting things that don't need to be quoted)
-    /// and incorrect (e.g. a single quote will itself be quoted with
-    /// a single quote). This method is mostly intended for logging
-    /// though, and it should work reasonably well for that.
+    /// alphanumeric or in `command_line_allowed_chars` (by default
+    /// the set `/-_,:.=+`), the component is quoted with `'` (single
+    /// quotes). This is both too aggressive (unnecessarily quoting
+    /// things that don't need to be quoted) and incorrect (e.g. a
+    /// single quote will itself be quoted with a single quote). This
+    /// method is mostly intended for logging though, and it should
+    /// work reasonably well for that. Use
+    /// [`Command::command_line_shell`] if correct quoting is needed.
+    ///
+    /// If `arg0` is set, it is shown in place of the program path.
     pub fn command_line_lossy(&self) -> String {
-        fn convert_word<S: AsRef<OsStr>>(word: S) -> String {
-            fn char_requires_quoting(c: char) -> bool {
-                if c.is_ascii_alphanumeric() {
-                    return false;
+        let program = self.arg0.as_deref().unwrap_or(self.program.as_os_str());
+        self.join_words(program, |word| self.quote_word_lossy(word))
+    }
+
+    /// Format as a space-separated command line that is prefixed with
+    /// the environment variables in `env` and `env_ordered`.
+    ///
+    /// Each variable is formatted as `KEY=VALUE`. Variables from `env`
+    /// are sorted by key and come first, followed by the variables in
+    /// `env_ordered` in order. `path_override` is shown as `PATH`.
+    ///
+    /// If `clear_env` is `true`, the output is also prefixed with
+    /// `env -i`, and the variables in `env_inherit` are shown with
+    /// their values in the current process. Otherwise each variable in
+    /// `env_remove` is shown as `-u KEY` after an `env` prefix.
+    ///
+    /// Unlike [`Command::command_line_lossy`], the program path is
+    /// always shown, even if `arg0` is set. Components are converted
+    /// and quoted in the same way as [`Command::command_line_lossy`].
+    pub fn command_line_lossy_with_env(&self) -> String {
+        let mut env = self.env.clone();
+        let mut ordered = self.env_ordered.clone();
+        for (key, _) in &ordered {
+            env.remove(key);
+        }
+        if let Some(path) = &self.path_override {
+            env.insert("PATH".into(), path.clone());
+            ordered.retain(|(key, _)| key != "PATH");
+        }
+
+        let mut vars = Vec::new();
+        if self.clear_env {
+            for key in &self.env_inherit {
+                if env.contains_key(key)
+                    || ordered.iter().any(|(k, _)| k == key)
+                {
+                    continue;
+                }
+                if let Some(value) = std::env::var_os(key) {
+                    vars.push((key.clone(), value));
                 }
-                let allowed_chars = "/-_,:.=+";
-                !allowed_chars.contains(c)
             }
+        }
+        let mut set: Vec<_> = env.into_iter().collect();
+        set.sort();
+        vars.extend(set);
+        vars.extend(ordered);
+        vars.retain(|(key, _)| !self.env_remove.contains(key));
+
+        let mut out = String::new();
+        if self.clear_env {
+            out.push_str("env -i ");
+        } else if !self.env_remove.is_empty() {
+            out.push_str("env ");
+            for key in &self.env_remove {
+                out.push_str("-u ");
+                out.push_str(&self.quote_word_lossy(key));
+                out.push(' ');
+            }
+        }
+        for (key, value) in vars {
+            out.push_str(&key.to_string_lossy());
+            out.push('=');
+            out.push_str(&self.quote_word_lossy(&value));
+            out.push(' ');
+        }
+        out.push_str(&self.join_words(self.program.as_os_str(), |word| {
+            self.quote_word_lossy(word)
+        }));
+        out
+    }
 
-            let s =
-                String::from_utf8_lossy(word.as_ref().as_bytes()).to_string();
-            if s.chars().any(char_requires_quoting) {
-                format!("'{}'", s)
+    /// Format as a command line that can be run in a POSIX shell.
+    ///
+    /// The program path and the arguments are converted to strings
+    /// with [`OsStr::to_string_lossy`].
+    ///
+    /// Components that only contain ASCII alphanumeric characters or
+    /// characters in the set `/-_,:.=+` are left unquoted. Other
+    /// components are quoted with `'` (single quotes), and single
+    /// quotes within them are escaped as `'\''`. Empty components
+    /// are formatted as `''`.
+    pub fn command_line_shell(&self) -> String {
+        fn convert_word(word: &OsStr) -> String {
+            let s = word.to_string_lossy();
+            if s.is_empty() {
+                "''".to_string()
+            } else if s
+                .chars()
+                .any(|c| char_requires_quoting(c, DEFAULT_ALLOWED_CHARS))
+            {
+                format!("'{}'", s.replace('\'', "'\\''"))
             } else {
-                s
+                s.to_string()
             }
         }
 
-        let mut out = convert_word(&self.program);
+        self.join_words(self.program.as_os_str(), convert_word)
+    }
+
+    /// Convert the program and each argument with `convert_word` and
+    /// join the results with spaces.
+    fn join_words(
+        &self,
+        program: &OsStr,
+        convert_word: impl Fn(&OsStr) -> String,
+    ) -> String {
+        let mut out = convert_word(program);
         for arg in &self.args {
             out.push(' ');
             out.push_str(&convert_word(arg));
         }
         out
     }
+
+    /// Convert `word` to a string for [`Command::command_line_lossy`],
+    /// quoting it if needed.
+    fn quote_word_lossy(&self, word: &OsStr) -> String {
+        let s = word.to_string_lossy().to_string();
+        if s.chars()
+            .any(|c| char_requires_quoting(c, &self.command_line_allowed_chars))
+        {
+            format!("'{}'", s)
+        } else {
+            s
+        }
+    }
+}
+
+/// Get the current time in UTC as an RFC 3339 timestamp.
+#[cfg(feature = "time")]
+fn timestamp() -> String {
+    time::OffsetDateTime::now_utc()
+        .format(&time::format_description::well_known::Rfc3339)
+        .unwrap_or_default()
+}
+
+/// Prefix each line of `msg` with the current time.
+#[cfg(feature = "time")]
+fn add_timestamps(msg: &str) -> String {
+    let now = timestamp();
+    msg.split('\n')
+        .map(|line| format!("{} {}", now, line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Characters other than ASCII alphanumerics that don't need to be
+/// quoted in a command line.
+const DEFAULT_ALLOWED_CHARS: &str = "/-_,:.=+";
+
+/// Check if a word in a command line needs to be quoted.
+fn char_requires_quoting(c: char, allowed_chars: &str) -> bool {
+    if c.is_ascii_alphanumeric() {
+        return false;
+    }
+    !allowed_chars.contains(c)
 }
 
 impl Default for Command {
@@ -429,30 +3124,579 @@ impl Default for Command {
         Self {
             program: PathBuf::new(),
             args: Vec::new(),
+            arg0: None,
+            expand_args: false,
+            expand_args_strict: false,
+            via_shell: None,
             dir: None,
+            verify_dir: false,
+            create_dir: false,
             log_to: LogTo::Stdout,
+            command_line_allowed_chars: Cow::Borrowed(DEFAULT_ALLOWED_CHARS),
+            #[cfg(feature = "time")]
+            log_timestamps: false,
             log_command: true,
+            #[cfg(feature = "logging")]
+            log_level: log::Level::Info,
             log_output_on_error: false,
+            log_output_always: false,
+            dry_run: false,
+            dry_run_sink: None,
             check: true,
+            success_codes: None,
+            success_predicate: None,
+            retries: 0,
+            retry_delay: None,
+            retry_backoff: None,
+            retry_backoff_max: None,
+            retry_on_timeout: false,
             capture: false,
             combine_output: false,
+            stderr_to_parent: false,
+            stdin: StdinSource::Inherit,
+            stdout_file: None,
+            stdout_file_append: false,
+            stderr_file: None,
+            stderr_file_append: false,
+            stdout_config: None,
+            stderr_config: None,
+            stdin_config: None,
+            capture_tagged: false,
+            tee_output: false,
+            tail_output_bytes: None,
+            normalize_output_newlines: false,
+            max_output_lines: None,
+            on_stdout_line: None,
+            on_stderr_line: None,
+            std_command_fn: None,
+            before_run: None,
+            after_run: None,
             clear_env: false,
+            env_inherit: Vec::new(),
             env: HashMap::new(),
+            env_ordered: Vec::new(),
+            env_remove: Vec::new(),
+            path_override: None,
+            new_process_group: false,
+            nice: None,
+            close_fds: false,
+            #[cfg(unix)]
+            uid: None,
+            #[cfg(unix)]
+            gid: None,
+            #[cfg(all(unix, feature = "pty"))]
+            use_pty: false,
+            kill_on_drop: false,
+            timeout: None,
+            deadline: None,
+            heartbeat_interval: None,
+            kill_grace: None,
+        }
+    }
+}
+
+impl fmt::Debug for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fn callback<T: ?Sized>(cb: &Option<Arc<T>>) -> Option<&str> {
+            cb.as_ref().map(|_| "<callback>")
+        }
+
+        let mut d = f.debug_struct("Command");
+        d.field("program", &self.program)
+            .field("args", &self.args)
+            .field("arg0", &self.arg0)
+            .field("expand_args", &self.expand_args)
+            .field("expand_args_strict", &self.expand_args_strict)
+            .field("via_shell", &self.via_shell)
+            .field("dir", &self.dir)
+            .field("verify_dir", &self.verify_dir)
+            .field("create_dir", &self.create_dir)
+            .field("log_to", &self.log_to)
+            .field(
+                "command_line_allowed_chars",
+                &self.command_line_allowed_chars,
+            );
+        #[cfg(feature = "time")]
+        d.field("log_timestamps", &self.log_timestamps);
+        d.field("log_command", &self.log_command);
+        #[cfg(feature = "logging")]
+        d.field("log_level", &self.log_level);
+        d.field("log_output_on_error", &self.log_output_on_error)
+            .field("log_output_always", &self.log_output_always)
+            .field("dry_run", &self.dry_run)
+            .field("dry_run_sink", &callback(&self.dry_run_sink))
+            .field("check", &self.check)
+            .field("success_codes", &self.success_codes)
+            .field("success_predicate", &callback(&self.success_predicate))
+            .field("retries", &self.retries)
+            .field("retry_delay", &self.retry_delay)
+            .field("retry_backoff", &self.retry_backoff)
+            .field("retry_backoff_max", &self.retry_backoff_max)
+            .field("retry_on_timeout", &self.retry_on_timeout)
+            .field("capture", &self.capture)
+            .field("combine_output", &self.combine_output)
+            .field("stderr_to_parent", &self.stderr_to_parent)
+            .field("stdin", &self.stdin)
+            .field("stdout_file", &self.stdout_file)
+            .field("stdout_file_append", &self.stdout_file_append)
+            .field("stderr_file", &self.stderr_file)
+            .field("stderr_file_append", &self.stderr_file_append)
+            .field("stdout_config", &self.stdout_config)
+            .field("stderr_config", &self.stderr_config)
+            .field("stdin_config", &self.stdin_config)
+            .field("capture_tagged", &self.capture_tagged)
+            .field("tee_output", &self.tee_output)
+            .field("tail_output_bytes", &self.tail_output_bytes)
+            .field("normalize_output_newlines", &self.normalize_output_newlines)
+            .field("max_output_lines", &self.max_output_lines)
+            .field("on_stdout_line", &callback(&self.on_stdout_line))
+            .field("on_stderr_line", &callback(&self.on_stderr_line))
+            .field("std_command_fn", &callback(&self.std_command_fn))
+            .field("before_run", &callback(&self.before_run))
+            .field("after_run", &callback(&self.after_run))
+            .field("clear_env", &self.clear_env)
+            .field("env_inherit", &self.env_inherit)
+            .field("env", &self.env)
+            .field("env_ordered", &self.env_ordered)
+            .field("env_remove", &self.env_remove)
+            .field("path_override", &self.path_override)
+            .field("new_process_group", &self.new_process_group)
+            .field("nice", &self.nice)
+            .field("close_fds", &self.close_fds);
+        #[cfg(unix)]
+        d.field("uid", &self.uid).field("gid", &self.gid);
+        #[cfg(all(unix, feature = "pty"))]
+        d.field("use_pty", &self.use_pty);
+        d.field("kill_on_drop", &self.kill_on_drop)
+            .field("timeout", &self.timeout)
+            .field("deadline", &self.deadline)
+            .field("heartbeat_interval", &self.heartbeat_interval)
+            .field("kill_grace", &self.kill_grace)
+            .finish()
+    }
+}
+
+/// Formats the command with [`Command::command_line_lossy`].
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.command_line_lossy())
+    }
+}
+
+impl PartialEq for Command {
+    fn eq(&self, other: &Self) -> bool {
+        // Compare the multiplier by its bits so that `Eq` holds.
+        fn backoff_bits(
+            backoff: Option<(Duration, f64)>,
+        ) -> Option<(Duration, u64)> {
+            backoff.map(|(delay, factor)| (delay, factor.to_bits()))
+        }
+
+        #[cfg(feature = "logging")]
+        if self.log_level != other.log_level {
+            return false;
+        }
+        #[cfg(unix)]
+        if self.uid != other.uid || self.gid != other.gid {
+            return false;
         }
+        #[cfg(all(unix, feature = "pty"))]
+        if self.use_pty != other.use_pty {
+            return false;
+        }
+        #[cfg(feature = "time")]
+        if self.log_timestamps != other.log_timestamps {
+            return false;
+        }
+
+        // Callbacks are not compared.
+        self.program == other.program
+            && self.args == other.args
+            && self.arg0 == other.arg0
+            && self.expand_args == other.expand_args
+            && self.expand_args_strict == other.expand_args_strict
+            && self.via_shell == other.via_shell
+            && self.dir == other.dir
+            && self.verify_dir == other.verify_dir
+            && self.create_dir == other.create_dir
+            && self.log_to == other.log_to
+            && self.command_line_allowed_chars
+                == other.command_line_allowed_chars
+            && self.log_command == other.log_command
+            && self.log_output_on_error == other.log_output_on_error
+            && self.log_output_always == other.log_output_always
+            && self.dry_run == other.dry_run
+            && self.check == other.check
+            && self.success_codes == other.success_codes
+            && self.retries == other.retries
+            && self.retry_delay == other.retry_delay
+            && backoff_bits(self.retry_backoff)
+                == backoff_bits(other.retry_backoff)
+            && self.retry_backoff_max == other.retry_backoff_max
+            && self.retry_on_timeout == other.retry_on_timeout
+            && self.capture == other.capture
+            && self.combine_output == other.combine_output
+            && self.stderr_to_parent == other.stderr_to_parent
+            && self.stdin == other.stdin
+            && self.stdout_file == other.stdout_file
+            && self.stdout_file_append == other.stdout_file_append
+            && self.stderr_file == other.stderr_file
+            && self.stderr_file_append == other.stderr_file_append
+            && self.stdout_config == other.stdout_config
+            && self.stderr_config == other.stderr_config
+            && self.stdin_config == other.stdin_config
+            && self.capture_tagged == other.capture_tagged
+            && self.tee_output == other.tee_output
+            && self.tail_output_bytes == other.tail_output_bytes
+            && self.normalize_output_newlines == other.normalize_output_newlines
+            && self.max_output_lines == other.max_output_lines
+            && self.clear_env == other.clear_env
+            && self.env_inherit == other.env_inherit
+            && self.env == other.env
+            && self.env_ordered == other.env_ordered
+            && self.env_remove == other.env_remove
+            && self.path_override == other.path_override
+            && self.new_process_group == other.new_process_group
+            && self.nice == other.nice
+            && self.close_fds == other.close_fds
+            && self.kill_on_drop == other.kill_on_drop
+            && self.timeout == other.timeout
+            && self.deadline == other.deadline
+            && self.heartbeat_interval == other.heartbeat_interval
+            && self.kill_grace == other.kill_grace
     }
 }
 
+impl Eq for Command {}
+
 impl From<&Command> for process::Command {
     fn from(cmd: &Command) -> Self {
-        let mut out = process::Command::new(&cmd.program);
-        out.args(&cmd.args);
+        let mut out = if let Some(shell) = &cmd.via_shell {
+            let mut out = process::Command::new(shell);
+            out.arg("-c").arg(cmd.command_line_shell());
+            out
+        } else {
+            let mut out = process::Command::new(&cmd.program);
+            if cmd.expand_args {
+                for arg in &cmd.args {
+                    out.arg(cmd.expand_arg(arg).unwrap_or_else(|(arg, _)| arg));
+                }
+            } else {
+                out.args(&cmd.args);
+            }
+            out
+        };
         if let Some(dir) = &cmd.dir {
             out.current_dir(dir);
         }
         if cmd.clear_env {
             out.env_clear();
+            for key in &cmd.env_inherit {
+                if let Some(value) = std::env::var_os(key) {
+                    out.env(key, value);
+                }
+            }
         }
         out.envs(&cmd.env);
+        for (key, value) in &cmd.env_ordered {
+            out.env(key, value);
+        }
+        if let Some(path) = &cmd.path_override {
+            out.env("PATH", path);
+        }
+        for key in &cmd.env_remove {
+            out.env_remove(key);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+
+            if let (Some(arg0), None) = (&cmd.arg0, &cmd.via_shell) {
+                out.arg0(arg0);
+            }
+            if cmd.new_process_group {
+                out.process_group(0);
+            }
+            if let Some(uid) = cmd.uid {
+                out.uid(uid);
+            }
+            if let Some(gid) = cmd.gid {
+                out.gid(gid);
+            }
+            if let Some(nice) = cmd.nice {
+                // SAFETY: `setpriority` is async-signal-safe.
+                unsafe {
+                    out.pre_exec(move || {
+                        let ret =
+                            libc::setpriority(libc::PRIO_PROCESS as _, 0, nice);
+                        if ret == 0 {
+                            Ok(())
+                        } else {
+                            Err(io::Error::last_os_error())
+                        }
+                    });
+                }
+            }
+            if cmd.close_fds {
+                // SAFETY: `sysconf` is called before forking.
+                let max_fd = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) };
+                let max_fd = libc::c_int::try_from(max_fd)
+                    .ok()
+                    .filter(|max_fd| *max_fd > 0)
+                    .unwrap_or(1024);
+                // SAFETY: only async-signal-safe functions are called.
+                unsafe {
+                    out.pre_exec(move || {
+                        set_cloexec_from(3, max_fd);
+                        Ok(())
+                    });
+                }
+            }
+        }
         out
     }
 }
+
+/// A sequence of commands in which the stdout of each command is
+/// connected to the stdin of the next command.
+///
+/// Create a pipeline with [`Command::pipe`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[must_use]
+pub struct Pipeline {
+    /// The commands in the pipeline.
+    pub commands: Vec<Command>,
+}
+
+impl Pipeline {
+    /// Add a command to the end of the pipeline.
+    pub fn pipe(mut self, next: Command) -> Self {
+        self.commands.push(next);
+        self
+    }
+
+    /// Run the commands in the pipeline.
+    ///
+    /// All of the commands are started, then waited on. The options
+    /// of each command apply to that command, but the stdout of every
+    /// command except the last goes to the next command rather than
+    /// being captured.
+    ///
+    /// The output of the last command is returned. If any command
+    /// fails, the error from the first failing command is returned.
+    ///
+    /// If any command has `dry_run` set, none of the commands are
+    /// run. Each command is logged as described for `dry_run`, and a
+    /// successful output of the last command is returned.
+    ///
+    /// The `retries` and `after_run` options of the commands are not
+    /// used.
+    ///
+    /// If a command fails to start, the commands that were already
+    /// started are killed and waited on before the error is returned.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `commands` is empty.
+    pub fn run(&self) -> Result<Output, Error> {
+        if self.commands.iter().any(|command| command.dry_run) {
+            let mut output = None;
+            for command in &self.commands {
+                output = Some(command.dry_run_output());
+            }
+            return Ok(output.expect("pipeline is empty"));
+        }
+
+        let mut children = Vec::new();
+        let mut stdin = None;
+        for (index, command) in self.commands.iter().enumerate() {
+            let spawn_result = if index + 1 < self.commands.len() {
+                os_pipe::pipe().into_run_error(command).and_then(
+                    |(reader, writer)| {
+                        let child =
+                            command.spawn_stage(stdin.take(), Some(writer))?;
+                        stdin = Some(reader);
+                        Ok(child)
+                    },
+                )
+            } else {
+                command.spawn_stage(stdin.take(), None)
+            };
+            match spawn_result {
+                Ok(child) => children.push(child),
+                Err(err) => {
+                    // Errors are ignored since the spawn error is the
+                    // one that is returned.
+                    for mut child in children {
+                        let _ = kill_child(
+                            &mut child.inner,
+                            child.command.new_process_group,
+                        );
+                        let _ = child.inner.wait();
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        let mut result = None;
+        for child in children {
+            let child_result = child.wait();
+            if !matches!(result, Some(Err(_))) {
+                result = Some(child_result);
+            }
+        }
+        result.expect("pipeline is empty")
+    }
+}
+
+/// A command that has been started with [`Command::spawn`].
+///
+/// If the command's `kill_on_drop` field is `true`, the child process
+/// is killed when this is dropped.
+#[derive(Debug)]
+pub struct Child {
+    command: Command,
+    inner: process::Child,
+    start: Instant,
+    stdin_writer: Option<thread::JoinHandle<io::Result<()>>>,
+    stdout_reader: Option<ReaderHandle>,
+    stderr_reader: Option<ReaderHandle>,
+    tagged: Option<TaggedChunks>,
+    output_lines: Option<Arc<AtomicUsize>>,
+}
+
+impl Child {
+    /// Get the command that this child was started from.
+    pub fn command(&self) -> &Command {
+        &self.command
+    }
+
+    /// Get the OS-assigned process identifier of the child.
+    pub fn id(&self) -> u32 {
+        self.inner.id()
+    }
+
+    /// Kill the child.
+    ///
+    /// If the command's `new_process_group` field is `true`, the
+    /// child's whole process group is killed.
+    pub fn kill(&mut self) -> Result<(), Error> {
+        kill_child(&mut self.inner, self.command.new_process_group)
+            .into_run_error(&self.command)
+    }
+
+    /// Wait for the child to finish.
+    ///
+    /// See [`Command::run`] for details of the output and errors.
+    pub fn wait(mut self) -> Result<Output, Error> {
+        let command = &self.command;
+
+        let on_heartbeat = |elapsed: Duration| {
+            command.log(
+                LogLevel::Command,
+                &format!(
+                    "still running after {:.1}s: {}",
+                    elapsed.as_secs_f64(),
+                    command.command_line_lossy()
+                ),
+            );
+        };
+        let over_limit = || match (&self.output_lines, command.max_output_lines)
+        {
+            (Some(lines), Some(max)) => lines.load(Ordering::Relaxed) > max,
+            _ => false,
+        };
+        let (status, killed) = wait_with_timeout(
+            &mut self.inner,
+            self.start,
+            command.effective_timeout(self.start),
+            command.new_process_group,
+            command.kill_grace,
+            command
+                .heartbeat_interval
+                .filter(|interval| !interval.is_zero())
+                .map(|interval| (interval, &on_heartbeat as &dyn Fn(Duration))),
+            self.output_lines
+                .as_ref()
+                .map(|_| &over_limit as &dyn Fn() -> bool),
+        )
+        .into_run_error(command)?;
+        let duration = self.start.elapsed();
+        if let Some(killed) = killed {
+            // Don't wait for the reader threads, the pipes may still
+            // be held open by other processes.
+            let mut out = Output {
+                stdout: reader_output_so_far(&self.stdout_reader),
+                stderr: reader_output_so_far(&self.stderr_reader),
+                tagged_output: self
+                    .tagged
+                    .as_ref()
+                    .map(|tagged| tagged.lock().unwrap().clone())
+                    .unwrap_or_default(),
+                status,
+                duration,
+                command: Some(command.clone()),
+            };
+            if command.normalize_output_newlines {
+                normalize_newlines(&mut out.stdout);
+                normalize_newlines(&mut out.stderr);
+            }
+            return Err(Error {
+                command: command.clone(),
+                kind: match killed {
+                    KillReason::Timeout => ErrorKind::Timeout(duration),
+                    KillReason::OutputLimit => ErrorKind::OutputLimit(
+                        command.max_output_lines.unwrap_or_default(),
+                    ),
+                },
+                output: Some(out),
+                resolved_program: None,
+            });
+        }
+
+        if let Some(stdin_writer) = self.stdin_writer.take() {
+            let result = stdin_writer.join().expect("writer thread panicked");
+            ignore_broken_pipe(result).into_run_error(command)?;
+        }
+
+        let out = Output {
+            stdout: join_reader(self.stdout_reader.take())
+                .into_run_error(command)?,
+            stderr: join_reader(self.stderr_reader.take())
+                .into_run_error(command)?,
+            tagged_output: self
+                .tagged
+                .take()
+                .map(|tagged| mem::take(&mut *tagged.lock().unwrap()))
+                .unwrap_or_default(),
+            status,
+            duration,
+            command: Some(command.clone()),
+        };
+        // The command may have exited before the limit was checked.
+        if over_limit() {
+            return Err(Error {
+                command: command.clone(),
+                kind: ErrorKind::OutputLimit(
+                    command.max_output_lines.unwrap_or_default(),
+                ),
+                output: Some(out),
+                resolved_program: None,
+            });
+        }
+        command.check_output(out)
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        if self.command.kill_on_drop {
+            // Errors are ignored since there is no way to report
+            // them. Nothing is killed if the child has already been
+            // reaped.
+            let _ = kill_child(&mut self.inner, self.command.new_process_group);
+            let _ = self.inner.wait();
+        }
+    }
+}
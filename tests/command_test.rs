@@ -1,12 +1,43 @@
 #[cfg(feature = "logging")]
 mod capture_logger {
     use log::{Level, LevelFilter, Metadata, Record};
-    use once_cell::sync::OnceCell;
-    use std::sync::{Arc, Mutex};
+    use std::cell::RefCell;
+    use std::sync::Once;
 
-    #[derive(Debug, Default)]
-    struct CapturedLogs {
-        logs: Arc<Mutex<Vec<(Level, String)>>>,
+    // Logs are captured per thread so that tests running in parallel
+    // don't see each other's logs.
+    thread_local! {
+        static CAPTURED_LOGS: RefCell<Vec<(Level, String)>> =
+            const { RefCell::new(Vec::new()) };
+        static CAPTURED_KEY_VALUES: RefCell<Vec<Vec<(String, String)>>> =
+            const { RefCell::new(Vec::new()) };
+    }
+
+    #[cfg(feature = "structured_logging")]
+    fn key_values(record: &Record) -> Vec<(String, String)> {
+        use log::kv::{Error, Key, Value, VisitSource};
+
+        struct Visitor(Vec<(String, String)>);
+
+        impl<'kvs> VisitSource<'kvs> for Visitor {
+            fn visit_pair(
+                &mut self,
+                key: Key<'kvs>,
+                value: Value<'kvs>,
+            ) -> Result<(), Error> {
+                self.0.push((key.to_string(), value.to_string()));
+                Ok(())
+            }
+        }
+
+        let mut visitor = Visitor(Vec::new());
+        record.key_values().visit(&mut visitor).unwrap();
+        visitor.0
+    }
+
+    #[cfg(not(feature = "structured_logging"))]
+    fn key_values(_: &Record) -> Vec<(String, String)> {
+        Vec::new()
     }
 
     struct Logger {}
@@ -17,107 +48,1763 @@ mod capture_logger {
         }
 
         fn log(&self, record: &Record) {
-            CAPTURED_LOGS
-                .get()
-                .unwrap()
-                .logs
-                .lock()
-                .unwrap()
-                .push((record.level(), record.args().to_string()));
+            CAPTURED_LOGS.with(|logs| {
+                logs.borrow_mut()
+                    .push((record.level(), record.args().to_string()))
+            });
+            CAPTURED_KEY_VALUES
+                .with(|kvs| kvs.borrow_mut().push(key_values(record)));
         }
 
         fn flush(&self) {}
     }
 
-    // TODO: can these be combined?
     static LOGGER: Logger = Logger {};
-    static CAPTURED_LOGS: OnceCell<CapturedLogs> = OnceCell::new();
+    static INIT: Once = Once::new();
 
     pub fn init() {
-        CAPTURED_LOGS.set(CapturedLogs::default()).unwrap();
-        log::set_logger(&LOGGER)
-            .map(|()| log::set_max_level(LevelFilter::Info))
-            .unwrap();
+        INIT.call_once(|| {
+            log::set_logger(&LOGGER)
+                .map(|()| log::set_max_level(LevelFilter::Trace))
+                .unwrap();
+        });
+        clear_logs();
     }
 
     pub fn get_logs() -> Vec<(Level, String)> {
-        CAPTURED_LOGS.get().unwrap().logs.lock().unwrap().clone()
+        CAPTURED_LOGS.with(|logs| logs.borrow().clone())
+    }
+
+    #[allow(dead_code)]
+    pub fn get_key_values() -> Vec<Vec<(String, String)>> {
+        CAPTURED_KEY_VALUES.with(|kvs| kvs.borrow().clone())
     }
 
     pub fn clear_logs() {
-        CAPTURED_LOGS.get().unwrap().logs.lock().unwrap().clear();
+        CAPTURED_LOGS.with(|logs| logs.borrow_mut().clear());
+        CAPTURED_KEY_VALUES.with(|kvs| kvs.borrow_mut().clear());
     }
 }
 
-use command_run::Command;
+use command_run::{Command, ErrorKind, StdinSource, Stream};
 use std::fs;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tempfile::TempDir;
 
-#[test]
-fn test_check() {
-    // Check, exit zero
-    let mut cmd = Command::new("true");
-    assert!(cmd.run().is_ok());
+#[test]
+fn test_check() {
+    // Check, exit zero
+    let mut cmd = Command::new("true");
+    assert!(cmd.run().is_ok());
+
+    // Check, exit non-zero
+    cmd.program = Path::new("false").into();
+    assert!(cmd.run().unwrap_err().is_exit_error());
+
+    // No check
+    cmd.check = false;
+    assert!(cmd.run().is_ok());
+}
+
+#[test]
+fn test_run_check() {
+    assert!(Command::new("true").run_check().is_ok());
+    assert!(Command::new("false")
+        .run_check()
+        .unwrap_err()
+        .is_exit_error());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_raw_status() -> Result<(), anyhow::Error> {
+    // WIFEXITED is true and WEXITSTATUS is the exit code.
+    let raw = Command::new("true").run()?.raw_status();
+    assert_eq!(raw & 0x7f, 0);
+    assert_eq!((raw >> 8) & 0xff, 0);
+
+    let raw = Command::with_args("sh", ["-c", "exit 3"])
+        .disable_check()
+        .run()?
+        .raw_status();
+    assert_eq!(raw & 0x7f, 0);
+    assert_eq!((raw >> 8) & 0xff, 3);
+    Ok(())
+}
+
+#[test]
+fn test_success_codes() -> Result<(), anyhow::Error> {
+    let mut cmd = Command::with_args("grep", ["needle", "/dev/null"]);
+    assert!(cmd.run().unwrap_err().is_exit_error());
+
+    cmd.set_success_codes([0, 1]);
+    assert_eq!(cmd.run()?.code(), Some(1));
+
+    // Codes not in the list are still an error
+    cmd.set_success_codes([1]);
+    cmd.program = Path::new("true").into();
+    assert!(cmd.run().unwrap_err().is_exit_error());
+
+    Ok(())
+}
+
+#[test]
+fn test_success_predicate() -> Result<(), anyhow::Error> {
+    let mut cmd = Command::with_args("sh", ["-c", "exit 2"]);
+    assert!(cmd.run().unwrap_err().is_exit_error());
+
+    cmd.set_success_predicate(|status| status.code() == Some(2));
+    assert_eq!(cmd.run()?.code(), Some(2));
+
+    // The predicate takes precedence over the success codes.
+    cmd.set_success_codes([0]);
+    assert_eq!(cmd.run()?.code(), Some(2));
+    cmd.args = vec!["-c".into(), "exit 0".into()];
+    assert!(cmd.run().unwrap_err().is_exit_error());
+
+    // Not compared
+    assert_eq!(cmd, cmd.clone().set_success_predicate(|_| true).clone());
+
+    Ok(())
+}
+
+#[test]
+fn test_output_code() -> Result<(), anyhow::Error> {
+    let output = Command::new("true").disable_check().run()?;
+    assert_eq!(output.code(), Some(0));
+    assert!(output.success());
+
+    let output = Command::new("false").disable_check().run()?;
+    assert_eq!(output.code(), Some(1));
+    assert!(!output.success());
+
+    Ok(())
+}
+
+#[test]
+fn test_signal() {
+    let err = Command::with_args("sh", ["-c", "kill -9 $$"])
+        .run()
+        .unwrap_err();
+    assert!(err.is_signal_error());
+    assert!(!err.is_exit_error());
+    assert!(matches!(err.kind, ErrorKind::Signal(9)));
+    assert_eq!(
+        err.to_string(),
+        "command 'sh -c 'kill -9 $$'' terminated by signal SIGKILL (9)"
+    );
+
+    let err = Command::with_args("sh", ["-c", "kill -SEGV $$"])
+        .run()
+        .unwrap_err();
+    assert!(err
+        .to_string()
+        .ends_with("terminated by signal SIGSEGV (11)"));
+}
+
+#[test]
+fn test_not_found() {
+    let err = Command::new("command-run-test-does-not-exist")
+        .run()
+        .unwrap_err();
+    assert!(err.is_run_error());
+    assert!(err.is_not_found());
+
+    let err = Command::new("false").run().unwrap_err();
+    assert!(!err.is_not_found());
+}
+
+#[test]
+fn test_error_source() {
+    use std::error::Error;
+
+    let err = Command::new("command-run-test-does-not-exist")
+        .run()
+        .unwrap_err();
+    let source = err.source().unwrap();
+    let source = source.downcast_ref::<std::io::Error>().unwrap();
+    assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+
+    let err = Command::new("false").run().unwrap_err();
+    assert!(err.source().is_none());
+}
+
+#[test]
+fn test_exit_code() {
+    let err = Command::new("false").run().unwrap_err();
+    assert_eq!(err.exit_code(), Some(1));
+
+    let err = Command::with_args("sh", ["-c", "exit 3"])
+        .run()
+        .unwrap_err();
+    assert_eq!(err.exit_code(), Some(3));
+
+    let err = Command::new("command-run-test-does-not-exist")
+        .run()
+        .unwrap_err();
+    assert_eq!(err.exit_code(), None);
+}
+
+#[test]
+fn test_exit_error_output() {
+    let mut cmd = Command::with_args("sh", ["-c", "echo oops >&2; exit 1"]);
+    let err = cmd.run().unwrap_err();
+    assert!(err.output.is_none());
+
+    cmd.capture = true;
+    let err = cmd.run().unwrap_err();
+    assert!(err.is_exit_error());
+    let output = err.output.unwrap();
+    assert_eq!(output.stderr_string_lossy(), "oops\n");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_error_clone() {
+    let err = Command::new("command-run-test-does-not-exist")
+        .run()
+        .unwrap_err();
+    let clone = err.clone();
+    assert!(clone.is_run_error());
+    assert!(clone.is_not_found());
+    assert_eq!(clone.command, err.command);
+    assert_eq!(clone.to_string(), err.to_string());
+
+    let err = Command::new("false").run().unwrap_err();
+    let clone = err.clone();
+    assert!(clone.is_exit_error());
+    assert_eq!(clone.command, err.command);
+}
+
+#[test]
+fn test_output_string() -> Result<(), anyhow::Error> {
+    let output = Command::with_args("sh", ["-c", "printf out; printf err >&2"])
+        .enable_capture()
+        .run()?;
+    assert_eq!(output.stdout_string()?, "out");
+    assert_eq!(output.stderr_string()?, "err");
+
+    let output = Command::with_args("printf", ["\\377"])
+        .enable_capture()
+        .run()?;
+    assert!(output.stdout_string().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_output_trimmed() -> Result<(), anyhow::Error> {
+    let output = Command::with_args("echo", ["foo"]).enable_capture().run()?;
+    assert_eq!(output.stdout_string_lossy_trimmed(), "foo");
+
+    let output = Command::with_args("sh", ["-c", "echo error >&2"])
+        .enable_capture()
+        .run()?;
+    assert_eq!(output.stderr_string_lossy_trimmed(), "error");
+    Ok(())
+}
+
+#[test]
+fn test_output_no_trailing_newline() -> Result<(), anyhow::Error> {
+    let check = |input: &str, expected: &[u8]| -> Result<(), anyhow::Error> {
+        let output = Command::with_args("printf", [input])
+            .enable_capture()
+            .run()?;
+        assert_eq!(output.stdout_no_trailing_newline(), expected);
+        Ok(())
+    };
+    check("foo", b"foo")?;
+    check("foo\\n", b"foo")?;
+    check("foo\\r\\n", b"foo")?;
+    check("foo\\n\\n", b"foo\n")?;
+    Ok(())
+}
+
+#[cfg(feature = "encoding")]
+#[test]
+fn test_output_encoding() -> Result<(), anyhow::Error> {
+    let output = Command::with_args("printf", ["caf\\351"])
+        .enable_capture()
+        .run()?;
+    assert_eq!(output.stdout, b"caf\xe9");
+    assert_eq!(
+        output.stdout_string_with_encoding(encoding_rs::WINDOWS_1252),
+        "café"
+    );
+    assert_eq!(output.stdout_string_lossy(), "caf\u{fffd}");
+    Ok(())
+}
+
+#[test]
+fn test_output_lines() -> Result<(), anyhow::Error> {
+    let lines = |input: &str| -> Result<Vec<String>, anyhow::Error> {
+        let output = Command::with_args("printf", [input])
+            .enable_capture()
+            .run()?;
+        Ok(output
+            .stdout_lines()
+            .map(|line| line.into_owned())
+            .collect())
+    };
+    assert!(lines("")?.is_empty());
+    assert_eq!(lines("a")?, ["a"]);
+    assert_eq!(lines("a\\nb\\r\\nc\\n")?, ["a", "b", "c"]);
+    assert_eq!(lines("a\\n\\nb")?, ["a", "", "b"]);
+    assert_eq!(lines("\\n")?, [""]);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_output_osstring() -> Result<(), anyhow::Error> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let output = Command::with_args("printf", ["a\\377b"])
+        .enable_capture()
+        .run()?;
+    let s = output.stdout_osstring();
+    assert!(s.to_str().is_none());
+    assert_eq!(s.as_bytes(), b"a\xffb");
+    Ok(())
+}
+
+#[test]
+fn test_output_is_empty() -> Result<(), anyhow::Error> {
+    let output = Command::new("true").enable_capture().run()?;
+    assert!(output.stdout_is_empty());
+    assert!(output.stderr_is_empty());
+    assert_eq!(output.stdout_len(), 0);
+
+    let output = Command::with_args("echo", ["hello"])
+        .enable_capture()
+        .run()?;
+    assert!(!output.stdout_is_empty());
+    assert!(output.stderr_is_empty());
+    assert_eq!(output.stdout_len(), 6);
+    Ok(())
+}
+
+#[test]
+fn test_output_split_nul() -> Result<(), anyhow::Error> {
+    let split = |input: &str| -> Result<Vec<Vec<u8>>, anyhow::Error> {
+        let output = Command::with_args("printf", [input])
+            .enable_capture()
+            .run()?;
+        Ok(output
+            .stdout_split_nul()
+            .map(|part| part.to_vec())
+            .collect())
+    };
+    assert_eq!(split("a\\0b\\0c\\0")?, [b"a", b"b", b"c"]);
+    assert_eq!(split("a\\0\\0b")?, [&b"a"[..], b"", b"b"]);
+    assert!(split("")?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_expand_args() -> Result<(), anyhow::Error> {
+    // Set in this process but removed from the child's environment
+    if !rerun_test_with_env(
+        "test_expand_args",
+        &[("COMMAND_RUN_TEST_REMOVED", "removed")],
+    )? {
+        return Ok(());
+    }
+
+    let mut cmd = Command::with_args(
+        "echo",
+        ["--x=${FOO}", "${COMMAND_RUN_TEST_REMOVED}."],
+    );
+    cmd.env.insert("FOO".into(), "bar".into());
+    cmd.env_remove.push("COMMAND_RUN_TEST_REMOVED".into());
+    cmd.capture = true;
+    cmd.expand_args = true;
+    assert_eq!(cmd.run()?.stdout_string_lossy(), "--x=bar .\n");
+
+    // `path_override` is used for `PATH`
+    let mut path_cmd = Command::with_args("echo", ["${PATH}"]);
+    path_cmd.path_override = Some("/bin:/usr/bin".into());
+    path_cmd.capture = true;
+    path_cmd.expand_args = true;
+    assert_eq!(path_cmd.run()?.stdout_string_lossy(), "/bin:/usr/bin\n");
+
+    // Not expanded by default.
+    cmd.expand_args = false;
+    assert_eq!(
+        cmd.run()?.stdout_string_lossy(),
+        "--x=${FOO} ${COMMAND_RUN_TEST_REMOVED}.\n"
+    );
+
+    cmd.expand_args = true;
+    cmd.expand_args_strict = true;
+    cmd.clear_env = true;
+    assert!(cmd.run().unwrap_err().is_run_error());
+    Ok(())
+}
+
+#[test]
+fn test_output_command() -> Result<(), anyhow::Error> {
+    let cmd = Command::with_args("echo", ["a b"]);
+    let output = cmd.run()?;
+    assert_eq!(output.command.as_ref(), Some(&cmd));
+    assert_eq!(output.command.unwrap().command_line_lossy(), "echo 'a b'");
+    Ok(())
+}
+
+#[test]
+fn test_run_typed() -> Result<(), anyhow::Error> {
+    use command_run::RunResult;
+
+    let mut cmd = Command::with_args("echo", ["hi"]);
+    match cmd.run_typed()? {
+        RunResult::Status(status) => assert!(status.success()),
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    cmd.enable_capture();
+    match cmd.run_typed()? {
+        RunResult::Captured(output) => {
+            assert_eq!(output.stdout_string_lossy(), "hi\n")
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+    Ok(())
+}
+
+#[test]
+fn test_run_get_string() -> Result<(), anyhow::Error> {
+    let cmd = Command::with_args("echo", ["hi"]);
+    assert_eq!(cmd.run_get_string()?, "hi");
+    assert!(!cmd.capture);
+    Ok(())
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn test_run_json() -> Result<(), anyhow::Error> {
+    #[derive(Debug, Eq, PartialEq, serde::Deserialize)]
+    struct Data {
+        a: i32,
+    }
+
+    let data: Data = Command::with_args("echo", [r#"{"a":1}"#]).run_json()?;
+    assert_eq!(data, Data { a: 1 });
+
+    let err = Command::with_args("echo", ["not json"])
+        .run_json::<Data>()
+        .unwrap_err();
+    assert!(err.is_parse_error());
+    assert_eq!(err.output.unwrap().stdout_string_lossy(), "not json\n");
+    Ok(())
+}
+
+#[test]
+fn test_split_str() {
+    assert!(Command::from_whitespace_separated_str("").is_none());
+    assert!(Command::from_whitespace_separated_str(" ").is_none());
+    assert_eq!(
+        Command::from_whitespace_separated_str("abc"),
+        Some(Command::new("abc"))
+    );
+    assert_eq!(
+        Command::from_whitespace_separated_str("abc 123 456"),
+        Some(Command::with_args("abc", ["123", "456"]))
+    );
+}
+
+#[cfg(feature = "shell-words")]
+#[test]
+fn test_parse() {
+    use command_run::ParseError;
+
+    assert_eq!(
+        Command::parse("git commit -m 'hello world'"),
+        Ok(Command::with_args("git", ["commit", "-m", "hello world"]))
+    );
+    assert_eq!(
+        Command::parse(r#"echo "say \"hi\"" it\'s"#),
+        Ok(Command::with_args("echo", [r#"say "hi""#, "it's"]))
+    );
+    assert_eq!(Command::parse(""), Err(ParseError::Empty));
+    assert_eq!(Command::parse("  "), Err(ParseError::Empty));
+    assert_eq!(
+        Command::parse("echo 'abc"),
+        Err(ParseError::MissingClosingQuote)
+    );
+}
+
+#[test]
+fn test_from_argv() {
+    let argv: Vec<std::ffi::OsString> = vec!["a".into(), "b c".into()];
+    assert_eq!(
+        Command::from_argv(argv),
+        Some(Command::with_args("a", ["b c"]))
+    );
+    assert_eq!(Command::from_argv(["a"]), Some(Command::new("a")));
+    assert!(Command::from_argv(Vec::<String>::new()).is_none());
+}
+
+#[test]
+fn test_with_program() {
+    let mut template = Command::with_args("a", ["b"]);
+    template.enable_capture().disable_check().set_dir("/tmp");
+
+    let cmd = template.with_program("c");
+    assert_eq!(cmd.program, Path::new("c"));
+    assert!(cmd.args.is_empty());
+    assert!(cmd.capture);
+    assert!(!cmd.check);
+    assert_eq!(cmd.dir, template.dir);
+}
+
+#[test]
+fn test_set_program() -> Result<(), anyhow::Error> {
+    let mut cmd = Command::default();
+    cmd.set_program("echo").add_arg("hi").enable_capture();
+    assert_eq!(cmd.program, Path::new("echo"));
+    assert_eq!(cmd.run()?.stdout_string_lossy(), "hi\n");
+    Ok(())
+}
+
+#[test]
+fn test_from_std() {
+    let mut std_cmd = std::process::Command::new("a");
+    std_cmd
+        .args(["b", "c d"])
+        .current_dir("/tmp")
+        .env("X", "1")
+        .env_remove("Y");
+
+    let cmd = Command::from_std(std_cmd);
+    assert_eq!(cmd.command_line_lossy(), "a b 'c d'");
+    assert_eq!(cmd.dir, Some("/tmp".into()));
+    assert_eq!(cmd.env.get(std::ffi::OsStr::new("X")), Some(&"1".into()));
+    assert_eq!(cmd.env_remove, ["Y"]);
+}
+
+#[test]
+fn test_args() -> Result<(), anyhow::Error> {
+    let out = Command::with_args("echo", ["hello", "world"])
+        .enable_capture()
+        .run()?;
+    assert_eq!(out.stdout, b"hello world\n");
+    Ok(())
+}
+
+#[test]
+fn test_by_value_builders() -> Result<(), anyhow::Error> {
+    let tmp_dir = TempDir::new()?;
+    let output = Command::new("sh")
+        .arg("-c")
+        .args(["echo $X $Y $(basename $PWD)"])
+        .env("X", "x")
+        .envs([("Y", "y")])
+        .dir(tmp_dir.path())
+        .capture()
+        .run()?;
+    let dir_name = tmp_dir.path().file_name().unwrap().to_string_lossy();
+    assert_eq!(output.stdout_string_lossy(), format!("x y {}\n", dir_name));
+    Ok(())
+}
+
+#[test]
+fn test_add_arg_variations() {
+    let mut cmd = Command::new("a");
+    cmd.add_arg("b");
+    cmd.add_arg_pair("c", Path::new("d"));
+    cmd.add_args(["e", "f", "g"]);
+    assert_eq!(cmd.command_line_lossy(), "a b c d e f g");
+}
+
+#[test]
+fn test_set_args() {
+    let mut cmd = Command::with_args("a", ["b", "c"]);
+    cmd.add_arg("d");
+    cmd.set_args(["e", "f"]);
+    assert_eq!(cmd.args, ["e", "f"]);
+
+    cmd.set_args(Vec::<String>::new());
+    assert!(cmd.args.is_empty());
+}
+
+#[test]
+fn test_add_flag_value() {
+    let mut cmd = Command::new("a");
+    cmd.add_flag_value("--name", "value")
+        .add_flag_value_eq("--other", Path::new("x y"));
+    assert_eq!(cmd.args, ["--name", "value", "--other=x y"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_add_arg_bytes() -> Result<(), anyhow::Error> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let bytes = b"a\xffb";
+    let mut cmd = Command::new("printf");
+    cmd.add_arg("%s").add_arg_bytes(bytes);
+    assert_eq!(cmd.args[1].as_bytes(), bytes);
+
+    cmd.capture = true;
+    assert_eq!(cmd.run()?.stdout, bytes);
+    Ok(())
+}
+
+#[test]
+fn test_add_arg_if() {
+    let mut cmd = Command::new("a");
+    cmd.add_arg_if(true, "-v")
+        .add_arg_if(false, "-q")
+        .add_args_if(true, ["b", "c"])
+        .add_args_if(false, ["d", "e"]);
+    assert_eq!(cmd.command_line_lossy(), "a -v b c");
+}
+
+#[test]
+fn test_to_std() {
+    use std::ffi::OsStr;
+
+    let mut cmd = Command::with_args("echo", ["a", "b"]);
+    cmd.set_dir("/tmp");
+    cmd.env.insert("X".into(), "1".into());
+    cmd.env_remove.push("Y".into());
+
+    let std_cmd = cmd.to_std();
+    assert_eq!(std_cmd.get_program(), "echo");
+    assert_eq!(std_cmd.get_args().collect::<Vec<_>>(), ["a", "b"]);
+    assert_eq!(std_cmd.get_current_dir(), Some(Path::new("/tmp")));
+    let mut envs: Vec<_> = std_cmd.get_envs().collect();
+    envs.sort();
+    assert_eq!(
+        envs,
+        [
+            (OsStr::new("X"), Some(OsStr::new("1"))),
+            (OsStr::new("Y"), None)
+        ]
+    );
+}
+
+#[test]
+fn test_same_invocation() {
+    let cmd1 = Command::with_args("a", ["b"]);
+    let mut cmd2 = cmd1.clone();
+    cmd2.log_command = false;
+    cmd2.log_output_on_error = true;
+    assert!(cmd1.same_invocation(&cmd2));
+    assert_ne!(cmd1, cmd2);
+
+    cmd2.add_arg("c");
+    assert!(!cmd1.same_invocation(&cmd2));
+}
+
+#[test]
+fn test_command_line_allowed_chars() {
+    let mut cmd = Command::with_args("git", ["clone", "git@host:repo"]);
+    assert_eq!(cmd.command_line_lossy(), "git clone 'git@host:repo'");
+
+    cmd.command_line_allowed_chars += "@";
+    assert_eq!(cmd.command_line_lossy(), "git clone git@host:repo");
+    // Not used for shell quoting.
+    assert_eq!(cmd.command_line_shell(), "git clone 'git@host:repo'");
+
+    cmd.command_line_allowed_chars = "".into();
+    assert_eq!(cmd.command_line_lossy(), "git clone 'git@host:repo'");
+    assert_eq!(
+        Command::with_args("a", ["b-c"]).command_line_lossy(),
+        "a b-c"
+    );
+}
+
+#[test]
+fn test_command_line() {
+    assert_eq!(Command::new("test").command_line_lossy(), "test");
+    assert_eq!(
+        Command::with_args("test", ["hello", "world"]).command_line_lossy(),
+        "test hello world"
+    );
+
+    assert_eq!(
+        Command::with_args("a b", ["c d", "e"]).command_line_lossy(),
+        "'a b' 'c d' e"
+    );
+
+    // Check that some special characters do not cause quoting
+    assert_eq!(
+        Command::with_args("a", ["-_/,:.=+"]).command_line_lossy(),
+        "a -_/,:.=+"
+    );
+
+    // Display uses the same format
+    assert_eq!(format!("{}", Command::with_args("a b", ["c"])), "'a b' c");
+}
+
+#[test]
+fn test_command_line_with_env() {
+    let mut cmd = Command::with_args("a", ["b"]);
+    cmd.set_env("Y", "c d").set_env("X", "1");
+    assert_eq!(cmd.command_line_lossy_with_env(), "X=1 Y='c d' a b");
+
+    cmd.enable_clear_env();
+    assert_eq!(cmd.command_line_lossy_with_env(), "env -i X=1 Y='c d' a b");
+
+    // Inherited variables are shown with their current values, unless
+    // overridden or removed
+    let path =
+        Command::new(std::env::var("PATH").unwrap()).command_line_lossy();
+    cmd.env_inherit = vec!["PATH".into(), "X".into()];
+    assert_eq!(
+        cmd.command_line_lossy_with_env(),
+        format!("env -i PATH={path} X=1 Y='c d' a b")
+    );
+    cmd.env_remove("PATH");
+    assert_eq!(cmd.command_line_lossy_with_env(), "env -i X=1 Y='c d' a b");
+
+    // Removed variables
+    cmd.clear_env = false;
+    cmd.env_remove("Y").env_remove("Z");
+    assert_eq!(
+        cmd.command_line_lossy_with_env(),
+        "env -u PATH -u Y -u Z X=1 a b"
+    );
+
+    // The program is shown instead of `arg0`
+    cmd.arg0 = Some("c".into());
+    assert_eq!(
+        cmd.command_line_lossy_with_env(),
+        "env -u PATH -u Y -u Z X=1 a b"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_arg0() -> Result<(), anyhow::Error> {
+    // Without an extra argument, `sh -c` sets `$0` to its argv[0].
+    let mut cmd = Command::with_args("sh", ["-c", "echo $0"]);
+    cmd.enable_capture();
+    assert_eq!(cmd.run()?.stdout_string_lossy(), "sh\n");
+
+    cmd.arg0 = Some("custom-name".into());
+    assert_eq!(cmd.run()?.stdout_string_lossy(), "custom-name\n");
+    assert_eq!(cmd.command_line_lossy(), "custom-name -c 'echo $0'");
+    assert_eq!(cmd.command_line_shell(), "sh -c 'echo $0'");
+    Ok(())
+}
+
+#[test]
+fn test_env_ordered() -> Result<(), anyhow::Error> {
+    let mut cmd = Command::with_args("sh", ["-c", "echo $X $Y"]);
+    cmd.capture = true;
+    cmd.set_env("X", "0").set_env("Z", "z");
+    cmd.add_env_ordered("Y", "1")
+        .add_env_ordered("X", "1")
+        .add_env_ordered("X", "2");
+    assert_eq!(cmd.run()?.stdout_string_lossy(), "2 1\n");
+    assert_eq!(
+        cmd.command_line_lossy_with_env(),
+        "Z=z Y=1 X=1 X=2 sh -c 'echo $X $Y'"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_command_line_shell() -> Result<(), anyhow::Error> {
+    assert_eq!(
+        Command::with_args("a", ["-_/,:.=+", ""]).command_line_shell(),
+        "a -_/,:.=+ ''"
+    );
+    assert_eq!(
+        Command::with_args("a b", ["it's"]).command_line_shell(),
+        r"'a b' 'it'\''s'"
+    );
+
+    // Check that the command line round-trips through the shell
+    let args = ["it's", "a b", "x\ny", "", "'", "$HOME", "\\"];
+    let mut cmd = Command::new("printf");
+    cmd.add_arg("%s|");
+    cmd.add_args(args);
+    let output = Command::with_args("sh", ["-c", &cmd.command_line_shell()])
+        .enable_capture()
+        .run()?;
+    assert_eq!(output.stdout_string_lossy(), format!("{}|", args.join("|")));
+
+    Ok(())
+}
+
+#[test]
+fn test_set_env() -> Result<(), anyhow::Error> {
+    let output = Command::with_args("printenv", ["TEST_VAR1", "TEST_VAR2"])
+        .set_env("TEST_VAR1", "a")
+        .set_envs([("TEST_VAR2", "b")])
+        .enable_capture()
+        .run()?;
+    assert_eq!(output.stdout_string_lossy(), "a\nb\n");
+    Ok(())
+}
+
+#[test]
+fn test_clear_env() -> Result<(), anyhow::Error> {
+    let output = Command::new("env")
+        .enable_clear_env()
+        .set_env("TEST_VAR", "a")
+        .enable_capture()
+        .run()?;
+    assert_eq!(output.stdout_string_lossy(), "TEST_VAR=a\n");
+    Ok(())
+}
+
+#[test]
+fn test_env_inherit() -> Result<(), anyhow::Error> {
+    let home = std::env::var("HOME")?;
+    let mut cmd = Command::new("env");
+    cmd.enable_clear_env().enable_capture();
+    cmd.env_inherit = vec!["HOME".into(), "COMMAND_RUN_TEST_UNSET".into()];
+    assert_eq!(cmd.run()?.stdout_string_lossy(), format!("HOME={}\n", home));
+
+    // Explicit variables are applied after inherited ones.
+    cmd.set_env("HOME", "/other");
+    assert_eq!(cmd.run()?.stdout_string_lossy(), "HOME=/other\n");
+    Ok(())
+}
+
+#[test]
+fn test_with_std_command() -> Result<(), anyhow::Error> {
+    let mut cmd = Command::with_args("printenv", ["TEST_VAR"]);
+    cmd.with_std_command(|cmd| {
+        cmd.env("TEST_VAR", "a");
+    });
+    let output = cmd.enable_capture().run()?;
+    assert_eq!(output.stdout_string_lossy(), "a\n");
+
+    // The callback is not compared
+    assert_eq!(
+        cmd,
+        Command::with_args("printenv", ["TEST_VAR"])
+            .enable_capture()
+            .clone()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_inherit_env_prefix() -> Result<(), anyhow::Error> {
+    if !rerun_test_with_env(
+        "test_inherit_env_prefix",
+        &[("MYPREFIX_FOO", "1"), ("OTHERPREFIX_BAR", "2")],
+    )? {
+        return Ok(());
+    }
+
+    let mut cmd = Command::new("a");
+    cmd.inherit_env_prefix("MYPREFIX_");
+    assert_eq!(
+        cmd.env.get(std::ffi::OsStr::new("MYPREFIX_FOO")),
+        Some(&"1".into())
+    );
+    assert!(!cmd
+        .env
+        .contains_key(std::ffi::OsStr::new("OTHERPREFIX_BAR")));
+    assert!(cmd
+        .env
+        .keys()
+        .all(|key| key.to_string_lossy().starts_with("MYPREFIX_")));
+    Ok(())
+}
+
+/// Run the test `name` again in a child process, with the environment
+/// variables in `vars` set. This is used instead of setting variables
+/// in this process, which is not safe while other tests are running.
+///
+/// Returns `true` if this is already the child process, in which case
+/// the caller should continue with the test.
+fn rerun_test_with_env(
+    name: &str,
+    vars: &[(&str, &str)],
+) -> Result<bool, anyhow::Error> {
+    const CHILD_VAR: &str = "COMMAND_RUN_TEST_CHILD";
+    if std::env::var_os(CHILD_VAR).is_some() {
+        return Ok(true);
+    }
+
+    let mut cmd =
+        Command::with_args(std::env::current_exe()?, ["--exact", name]);
+    cmd.set_env(CHILD_VAR, "1");
+    for (key, value) in vars {
+        cmd.set_env(key, value);
+    }
+    let (output, success) = cmd.run_allow_failure()?;
+    assert!(success, "{}", output.stdout_string_lossy());
+    Ok(false)
+}
+
+#[test]
+fn test_env_remove() -> Result<(), anyhow::Error> {
+    if !rerun_test_with_env(
+        "test_env_remove",
+        &[("COMMAND_RUN_TEST_ENV_REMOVE", "1")],
+    )? {
+        return Ok(());
+    }
+
+    let mut cmd =
+        Command::with_args("printenv", ["COMMAND_RUN_TEST_ENV_REMOVE"]);
+    cmd.enable_capture();
+    assert_eq!(cmd.run()?.stdout_string_lossy(), "1\n");
+
+    cmd.env_remove("COMMAND_RUN_TEST_ENV_REMOVE");
+    cmd.check = false;
+    let output = cmd.run()?;
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_set_path() -> Result<(), anyhow::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp_dir = TempDir::new()?;
+    let prog = tmp_dir.path().join("command-run-test-fake");
+    fs::write(&prog, "#!/bin/sh\necho fake\n")?;
+    fs::set_permissions(&prog, fs::Permissions::from_mode(0o755))?;
+
+    let mut cmd = Command::new("command-run-test-fake");
+    cmd.enable_capture();
+    assert!(cmd.run().unwrap_err().is_run_error());
+
+    // On Unix the program is looked up in the child's PATH.
+    cmd.set_path(tmp_dir.path());
+    assert_eq!(cmd.run()?.stdout_string_lossy(), "fake\n");
+    assert_eq!(
+        cmd.command_line_lossy_with_env(),
+        format!("PATH={} command-run-test-fake", tmp_dir.path().display())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_resolve_program() {
+    let ls = Command::new("ls").resolve_program().unwrap();
+    assert!(ls.is_absolute());
+    assert!(ls.ends_with("ls"));
+
+    assert_eq!(
+        Command::new("./a/b").resolve_program().unwrap(),
+        Path::new("./a/b")
+    );
+
+    assert!(Command::new("command-run-test-does-not-exist")
+        .resolve_program()
+        .is_none());
+
+    let mut cmd = Command::new("ls");
+    cmd.set_path("");
+    assert!(cmd.resolve_program().is_none());
+}
+
+#[test]
+fn test_verify_dir() -> Result<(), anyhow::Error> {
+    let tmp_dir = TempDir::new()?;
+    let missing = tmp_dir.path().join("missing");
+
+    let mut cmd = Command::new("true");
+    cmd.set_dir(&missing);
+    cmd.verify_dir = true;
+    let err = cmd.run().unwrap_err();
+    assert!(err.is_run_error());
+    assert!(err.to_string().contains(&missing.display().to_string()));
+
+    cmd.set_dir(tmp_dir.path());
+    assert!(cmd.run().is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn test_create_dir() -> Result<(), anyhow::Error> {
+    let tmp_dir = TempDir::new()?;
+    let nested = tmp_dir.path().join("a/b/c");
+
+    let mut cmd = Command::new("pwd");
+    cmd.set_dir(&nested);
+    cmd.capture = true;
+    cmd.create_dir = true;
+    let output = cmd.run()?;
+    assert!(nested.is_dir());
+    assert_eq!(
+        Path::new(output.stdout_string_lossy().trim_end()).canonicalize()?,
+        nested.canonicalize()?
+    );
+
+    // Running again with an existing directory is fine.
+    assert!(cmd.run().is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn test_run_capture() -> Result<(), anyhow::Error> {
+    let cmd = Command::with_args("echo", ["hello"]);
+    assert!(!cmd.capture);
+    let output = cmd.run_capture()?;
+    assert_eq!(output.stdout_string_lossy(), "hello\n");
+    assert!(!cmd.capture);
+    Ok(())
+}
+
+#[test]
+fn test_run_allow_failure() -> Result<(), anyhow::Error> {
+    let (output, success) = Command::new("false").run_allow_failure()?;
+    assert!(!success);
+    assert_eq!(output.code(), Some(1));
+
+    let (output, success) =
+        Command::with_args("echo", ["hi"]).run_allow_failure()?;
+    assert!(success);
+    assert_eq!(output.stdout_string_lossy(), "hi\n");
+
+    // `success_codes` is not used
+    let mut cmd = Command::new("false");
+    cmd.success_codes = Some(vec![1]);
+    let (_, success) = cmd.run_allow_failure()?;
+    assert!(!success);
+
+    // A timeout is not an error
+    let mut cmd = Command::with_args("sh", ["-c", "echo hi; sleep 10"]);
+    cmd.timeout = Some(Duration::from_millis(500));
+    let (output, success) = cmd.run_allow_failure()?;
+    assert!(!success);
+    assert_eq!(output.stdout_string_lossy(), "hi\n");
+
+    let err = Command::new("command-run-test-does-not-exist")
+        .run_allow_failure()
+        .unwrap_err();
+    assert!(err.is_run_error());
+    Ok(())
+}
+
+#[test]
+fn test_run_with_input() -> Result<(), anyhow::Error> {
+    let output = Command::new("cat").run_with_input("hello\n")?;
+    assert_eq!(output.stdout, b"hello\n");
+    Ok(())
+}
+
+#[test]
+fn test_normalize_output_newlines() -> Result<(), anyhow::Error> {
+    let mut cmd = Command::with_args("printf", ["foo\\r\\nbar\\r\\n\\r"]);
+    cmd.capture = true;
+    assert_eq!(cmd.run()?.stdout, b"foo\r\nbar\r\n\r");
+
+    cmd.normalize_output_newlines = true;
+    assert_eq!(cmd.run()?.stdout, b"foo\nbar\n\r");
+    Ok(())
+}
+
+#[test]
+fn test_run_error_resolved_path() -> Result<(), anyhow::Error> {
+    let tmp_dir = TempDir::new()?;
+
+    // The program exists, but the directory does not.
+    let mut cmd = Command::new("true");
+    cmd.set_dir(tmp_dir.path().join("missing"));
+    let resolved = cmd.resolve_program().unwrap();
+    assert!(resolved.is_absolute());
+    let mut err = cmd.run().unwrap_err();
+    assert!(err.is_run_error());
+    assert_eq!(err.resolved_program.as_ref(), Some(&resolved));
+    // The path is found when the error is created, not when it is
+    // formatted.
+    err.command.path_override = Some("/nonexistent".into());
+    assert!(err.to_string().starts_with(&format!(
+        "failed to run 'true' ({}): ",
+        resolved.display()
+    )));
+
+    // Not shown if the command is run via a shell.
+    cmd.via_shell = Some("sh".into());
+    let err = cmd.run().unwrap_err();
+    assert!(err.resolved_program.is_none());
+    assert!(err.to_string().starts_with("failed to run 'true': "));
+
+    // Not shown if the program is not found.
+    let err = Command::new("command-run-test-does-not-exist")
+        .run()
+        .unwrap_err();
+    assert!(err
+        .to_string()
+        .starts_with("failed to run 'command-run-test-does-not-exist': "));
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_close_fds() -> Result<(), anyhow::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    // Files are opened with the close-on-exec flag, but a duplicated
+    // descriptor is inherited by default.
+    let file = fs::File::open("/dev/null")?;
+    let fd = unsafe { libc::dup(file.as_raw_fd()) };
+    assert!(fd > 2);
+
+    let path = format!("/proc/self/fd/{fd}");
+    let mut cmd = Command::with_args("test", ["-e", &path]);
+    let result = cmd.run();
+    cmd.close_fds = true;
+    let result_with_close_fds = cmd.run();
+    unsafe { libc::close(fd) };
+
+    assert!(result.is_ok());
+    assert!(result_with_close_fds.unwrap_err().is_exit_error());
+
+    // Errors from exec are still reported.
+    cmd.program = "command-run-test-does-not-exist".into();
+    assert!(cmd.run().unwrap_err().is_not_found());
+    Ok(())
+}
+
+#[test]
+fn test_via_shell() -> Result<(), anyhow::Error> {
+    // `eval` is a shell builtin, not a program.
+    let mut cmd = Command::with_args("eval", ["echo $((1+1))"]);
+    cmd.capture = true;
+    assert!(cmd.run().unwrap_err().is_not_found());
+
+    cmd.via_shell = Some("sh".into());
+    assert_eq!(cmd.run()?.stdout_string_lossy(), "2\n");
+
+    // Arguments are quoted.
+    let output = Command::with_args("echo", ["$HOME", "a  b"])
+        .enable_capture()
+        .set_via_shell("sh")
+        .run()?;
+    assert_eq!(output.stdout_string_lossy(), "$HOME a  b\n");
+    Ok(())
+}
+
+#[test]
+fn test_pipeline() -> Result<(), anyhow::Error> {
+    let output = Command::with_args("echo", ["hello"])
+        .pipe(
+            Command::with_args("tr", ["a-z", "A-Z"])
+                .enable_capture()
+                .clone(),
+        )
+        .run()?;
+    assert_eq!(output.stdout_string_lossy(), "HELLO\n");
+
+    // Three stages
+    let output = Command::with_args("printf", ["b\\na\\nb\\n"])
+        .pipe(Command::new("sort"))
+        .pipe(Command::new("uniq").enable_capture().clone())
+        .run()?;
+    assert_eq!(output.stdout_string_lossy(), "a\nb\n");
+
+    // Failure in an early stage is an error
+    let err = Command::new("false")
+        .pipe(Command::new("cat"))
+        .run()
+        .unwrap_err();
+    assert!(err.is_exit_error());
+    assert_eq!(err.command.program, Path::new("false"));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_pipeline_spawn_failure() -> Result<(), anyhow::Error> {
+    let tmp_dir = TempDir::new()?;
+    let pid_path = tmp_dir.path().join("pid");
+
+    let mut first = Command::with_args(
+        "sh",
+        ["-c", "echo $$ > pid.tmp; mv pid.tmp pid; exec sleep 30"],
+    );
+    first.set_dir(tmp_dir.path());
+
+    // Wait for the first stage to start before failing to spawn the
+    // second stage.
+    let mut second = Command::new("nonexistent-command");
+    let pid_path_clone = pid_path.clone();
+    second.before_run = Some(Arc::new(move |_| {
+        while !pid_path_clone.exists() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }));
+
+    let err = first.pipe(second).run().unwrap_err();
+    assert!(err.is_run_error());
+
+    // The first stage has been killed and reaped
+    let pid: i32 = fs::read_to_string(&pid_path)?.trim().parse()?;
+    // SAFETY: `kill` has no memory safety requirements. Signal 0 only
+    // checks that the process exists.
+    assert_eq!(unsafe { libc::kill(pid, 0) }, -1);
+    assert_eq!(
+        std::io::Error::last_os_error().raw_os_error(),
+        Some(libc::ESRCH)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_retries() -> Result<(), anyhow::Error> {
+    let tmp_dir = TempDir::new()?;
+
+    // Fails on the first two attempts
+    let mut cmd = Command::with_args(
+        "sh",
+        [
+            "-c",
+            "n=$(($(cat count 2>/dev/null || echo 0) + 1)); \
+             echo $n > count; test $n -ge 3",
+        ],
+    );
+    cmd.set_dir(tmp_dir.path());
+    cmd.retry_delay = Some(Duration::from_millis(10));
+
+    cmd.set_retries(1);
+    assert!(cmd.run().unwrap_err().is_exit_error());
+    assert_eq!(fs::read_to_string(tmp_dir.path().join("count"))?, "2\n");
+
+    fs::remove_file(tmp_dir.path().join("count"))?;
+    cmd.set_retries(2);
+    assert!(cmd.run().is_ok());
+    assert_eq!(fs::read_to_string(tmp_dir.path().join("count"))?, "3\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_retry_backoff() -> Result<(), anyhow::Error> {
+    use std::time::Instant;
+
+    let tmp_dir = TempDir::new()?;
+
+    // Fails on the first two attempts
+    let mut cmd = Command::with_args(
+        "sh",
+        [
+            "-c",
+            "n=$(($(cat count 2>/dev/null || echo 0) + 1)); \
+             echo $n > count; test $n -ge 3",
+        ],
+    );
+    cmd.set_dir(tmp_dir.path());
+    cmd.set_retries(2);
+    cmd.retry_delay = Some(Duration::from_secs(10));
+    cmd.retry_backoff = Some((Duration::from_millis(100), 3.0));
+
+    // Waits 100ms, then 300ms.
+    let start = Instant::now();
+    assert!(cmd.run().is_ok());
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(400), "{elapsed:?}");
+    assert!(elapsed < Duration::from_secs(5), "{elapsed:?}");
+
+    // Waits 100ms twice.
+    fs::remove_file(tmp_dir.path().join("count"))?;
+    cmd.retry_backoff_max = Some(Duration::from_millis(100));
+    let start = Instant::now();
+    assert!(cmd.run().is_ok());
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(200), "{elapsed:?}");
+    assert!(elapsed < Duration::from_millis(400), "{elapsed:?}");
+
+    Ok(())
+}
+
+#[test]
+fn test_output_files() -> Result<(), anyhow::Error> {
+    let tmp_dir = TempDir::new()?;
+    let stdout_path = tmp_dir.path().join("stdout");
+    let stderr_path = tmp_dir.path().join("stderr");
+    fs::write(&stdout_path, "old contents")?;
+
+    let mut cmd = Command::with_args("sh", ["-c", "echo out; echo err >&2"]);
+    cmd.set_stdout_file(&stdout_path)
+        .set_stderr_file(&stderr_path);
+    cmd.run()?;
+    assert_eq!(fs::read_to_string(&stdout_path)?, "out\n");
+    assert_eq!(fs::read_to_string(&stderr_path)?, "err\n");
+
+    // Capture and file redirection can't be combined
+    cmd.enable_capture();
+    assert!(cmd.run().unwrap_err().is_run_error());
+
+    Ok(())
+}
+
+#[test]
+fn test_output_files_append() -> Result<(), anyhow::Error> {
+    let tmp_dir = TempDir::new()?;
+    let path = tmp_dir.path().join("log");
+
+    let mut cmd = Command::with_args("sh", ["-c", "echo out; echo err >&2"]);
+    cmd.set_stdout_file(&path).set_stderr_file(&path);
+    cmd.stdout_file_append = true;
+    cmd.stderr_file_append = true;
+    cmd.run()?;
+    cmd.args = vec!["-c".into(), "echo second".into()];
+    cmd.run()?;
+    assert_eq!(fs::read_to_string(&path)?, "out\nerr\nsecond\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_dry_run() -> Result<(), anyhow::Error> {
+    use command_run::LogTo;
+
+    let tmp_dir = TempDir::new()?;
+
+    let mut cmd = Command::with_args("rm", ["-rf"]);
+    cmd.add_arg(tmp_dir.path());
+    cmd.dry_run = true;
+    let output = cmd.enable_capture().run()?;
+    assert!(output.success());
+    assert!(output.stdout.is_empty());
+    assert!(tmp_dir.path().exists());
+
+    // Spawning a dry-run command is an error
+    assert!(cmd.spawn().unwrap_err().is_run_error());
+    assert!(tmp_dir.path().exists());
+
+    // No command in a pipeline is run if any of them is a dry run,
+    // but every command is logged in order
+    let buf = Arc::new(Mutex::new(Vec::new()));
+    let file_path = tmp_dir.path().join("file");
+    let mut touch = Command::with_args("touch", [&file_path]);
+    touch.log_to = LogTo::Writer(buf.clone());
+    cmd.log_to = LogTo::Writer(buf.clone());
+    let output = touch.clone().pipe(cmd.clone()).run()?;
+    assert!(output.success());
+    assert!(!file_path.exists());
+    assert!(tmp_dir.path().exists());
+    let log = String::from_utf8(buf.lock().unwrap().clone())?;
+    assert_eq!(
+        log,
+        format!(
+            "{}\n{}\n",
+            touch.command_line_lossy(),
+            cmd.command_line_lossy()
+        )
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_dry_run_sink() -> Result<(), anyhow::Error> {
+    let commands = Arc::new(Mutex::new(Vec::new()));
+    let commands_clone = commands.clone();
+    let record = move |cmd: &Command| {
+        commands_clone
+            .lock()
+            .unwrap()
+            .push(cmd.command_line_lossy());
+    };
+
+    for args in [["a", "b"], ["c", "d"]] {
+        let mut cmd = Command::with_args("true", args);
+        cmd.set_dry_run_sink(record.clone());
+        cmd.run()?;
+
+        cmd.program = "false".into();
+        cmd.dry_run = true;
+        cmd.run()?;
+    }
+
+    assert_eq!(*commands.lock().unwrap(), ["false a b", "false c d"]);
+    Ok(())
+}
+
+#[test]
+fn test_duration() -> Result<(), anyhow::Error> {
+    let output = Command::with_args("sleep", ["0.2"]).run()?;
+    assert!(output.duration >= Duration::from_millis(200));
+    Ok(())
+}
+
+#[test]
+fn test_stdin() -> Result<(), anyhow::Error> {
+    let mut cmd = Command::new("cat");
+    cmd.enable_capture().combine_output();
+
+    cmd.stdin = StdinSource::Null;
+    assert!(cmd.run()?.stdout.is_empty());
+
+    cmd.stdin = StdinSource::Bytes(b"hello".to_vec());
+    assert_eq!(cmd.run()?.stdout, b"hello");
+
+    let tmp_dir = TempDir::new()?;
+    let path = tmp_dir.path().join("input");
+    fs::write(&path, "12345")?;
+    let mut cmd = Command::with_args("wc", ["-c"]);
+    cmd.stdin = StdinSource::File(path);
+    let output = cmd.enable_capture().run()?;
+    assert_eq!(output.stdout_string_lossy().trim(), "5");
+
+    Ok(())
+}
+
+#[test]
+fn test_null_stdin() -> Result<(), anyhow::Error> {
+    let mut cmd = Command::with_args("sh", ["-c", "read x || echo eof"]);
+    cmd.stdin = StdinSource::Bytes(b"line\n".to_vec());
+    cmd.enable_capture().combine_output();
+    assert_eq!(cmd.run()?.stdout_string_lossy(), "");
+
+    cmd.null_stdin();
+    assert_eq!(cmd.run()?.stdout_string_lossy(), "eof\n");
+
+    cmd.inherit_stdin();
+    assert_eq!(cmd.stdin, StdinSource::Inherit);
+
+    Ok(())
+}
+
+#[test]
+fn test_timeout() {
+    let mut cmd = Command::with_args("sleep", ["10"]);
+    cmd.set_timeout(Duration::from_millis(100));
+    let err = cmd.run().unwrap_err();
+    assert!(err.is_timeout_error());
+
+    // The timeout should also work when capturing output
+    cmd.enable_capture();
+    let err = cmd.run().unwrap_err();
+    assert!(err.is_timeout_error());
+
+    // A command that finishes in time is not affected
+    cmd.args = vec!["0".into()];
+    assert!(cmd.run().is_ok());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_kill_grace() {
+    use std::os::unix::process::ExitStatusExt;
+    use std::time::Instant;
+
+    let status_after_timeout = |script: &str, grace: Option<Duration>| {
+        let mut cmd = Command::with_args("sh", ["-c", script]);
+        cmd.set_timeout(Duration::from_millis(200));
+        cmd.kill_grace = grace;
+        let err = cmd.run().unwrap_err();
+        assert!(err.is_timeout_error());
+        err.output.unwrap().status
+    };
+    let handles_term = "trap 'exit 3' TERM; sleep 5 & wait";
+
+    // Killed immediately by default.
+    let status = status_after_timeout(handles_term, None);
+    assert_eq!(status.signal(), Some(9));
+
+    // Given a chance to exit cleanly.
+    let start = Instant::now();
+    let status =
+        status_after_timeout(handles_term, Some(Duration::from_secs(3)));
+    assert_eq!(status.code(), Some(3));
+    assert!(start.elapsed() < Duration::from_secs(3));
+
+    // Killed after the grace period if SIGTERM is ignored.
+    let start = Instant::now();
+    let status = status_after_timeout(
+        "trap '' TERM; sleep 5 & wait",
+        Some(Duration::from_millis(300)),
+    );
+    assert_eq!(status.signal(), Some(9));
+    assert!(start.elapsed() >= Duration::from_millis(500));
+}
+
+#[test]
+fn test_deadline() {
+    let mut cmd = Command::with_args("sleep", ["5"]);
+    cmd.deadline = Some(std::time::Instant::now() + Duration::from_millis(100));
+    let start = std::time::Instant::now();
+    let err = cmd.run().unwrap_err();
+    assert!(err.is_timeout_error());
+    assert!(start.elapsed() < Duration::from_secs(4));
+
+    // The sooner of the deadline and timeout is used
+    cmd.deadline = Some(std::time::Instant::now() + Duration::from_secs(60));
+    cmd.set_timeout(Duration::from_millis(100));
+    assert!(cmd.run().unwrap_err().is_timeout_error());
+}
+
+#[test]
+fn test_timeout_partial_output() {
+    // The `sleep` is not exec'd, so it keeps stdout open after the
+    // shell is killed.
+    let mut cmd = Command::with_args("sh", ["-c", "echo marker; sleep 10"]);
+    cmd.enable_capture();
+    cmd.set_timeout(Duration::from_millis(500));
+    let start = std::time::Instant::now();
+    let err = cmd.run().unwrap_err();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(err.is_timeout_error());
+    let output = err.output.unwrap();
+    assert_eq!(output.stdout_string_lossy(), "marker\n");
+    assert_eq!(output.command, Some(cmd));
+}
+
+#[test]
+fn test_spawn() -> Result<(), anyhow::Error> {
+    let mut child = Command::with_args("sleep", ["5"]).spawn()?;
+    assert_ne!(child.id(), 0);
+    child.kill()?;
+    assert!(child.wait().unwrap_err().is_signal_error());
+
+    let child = Command::with_args("echo", ["hello"])
+        .enable_capture()
+        .spawn()?;
+    assert_eq!(child.wait()?.stdout_string_lossy(), "hello\n");
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_kill_on_drop() -> Result<(), anyhow::Error> {
+    let mut cmd = Command::with_args("sleep", ["30"]);
+    cmd.kill_on_drop = true;
+    let child = cmd.spawn()?;
+    let proc_path = format!("/proc/{}", child.id());
+    assert!(Path::new(&proc_path).exists());
+    drop(child);
+    assert!(!Path::new(&proc_path).exists());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_kill_on_drop_after_wait() -> Result<(), anyhow::Error> {
+    // Start a shell that leaves `sleep` running in its process group
+    let mut cmd =
+        Command::with_args("sh", ["-c", "sleep 30 > /dev/null 2>&1 & echo $!"]);
+    cmd.kill_on_drop = true;
+    cmd.new_process_group = true;
+    cmd.capture = true;
+    let output = cmd.spawn()?.wait()?;
+
+    // The child was reaped by `wait` before being dropped, so its
+    // process group must not be killed.
+    let sleep_pid: i32 = output.stdout_string_lossy().trim().parse()?;
+    // SAFETY: `kill` has no memory safety requirements.
+    unsafe {
+        // Signal 0 only checks that the process exists.
+        assert_eq!(libc::kill(sleep_pid, 0), 0);
+        libc::kill(sleep_pid, libc::SIGKILL);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_line_callbacks() -> Result<(), anyhow::Error> {
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let lines_clone = lines.clone();
+    let output = Command::with_args("seq", ["1000"])
+        .set_on_stdout_line(move |line| {
+            lines_clone.lock().unwrap().push(line.to_string())
+        })
+        .enable_capture()
+        .run()?;
+    let expected: Vec<String> = (1..=1000).map(|n| n.to_string()).collect();
+    assert_eq!(*lines.lock().unwrap(), expected);
+    assert_eq!(output.stdout_string_lossy(), expected.join("\n") + "\n");
+
+    // Final line without a newline, invalid UTF-8, and no capture
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let lines_clone = lines.clone();
+    let output = Command::with_args("printf", ["a\\377\\nb"])
+        .set_on_stderr_line(|_| panic!("unexpected stderr"))
+        .set_on_stdout_line(move |line| {
+            lines_clone.lock().unwrap().push(line.to_string())
+        })
+        .run()?;
+    assert_eq!(*lines.lock().unwrap(), ["a\u{fffd}", "b"]);
+    assert!(output.stdout.is_empty());
+
+    Ok(())
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn test_run_async() -> Result<(), anyhow::Error> {
+    let output = Command::with_args("echo", ["hello"])
+        .enable_capture()
+        .run_async()
+        .await?;
+    assert_eq!(output.stdout_string_lossy(), "hello\n");
+
+    let output = Command::with_args("sh", ["-c", "echo a; echo b >&2"])
+        .enable_capture()
+        .combine_output()
+        .run_async()
+        .await?;
+    assert_eq!(output.stdout_string_lossy(), "a\nb\n");
+
+    let mut cmd = Command::new("cat");
+    cmd.stdin = StdinSource::Bytes(b"hello".to_vec());
+    let output = cmd.enable_capture().run_async().await?;
+    assert_eq!(output.stdout, b"hello");
+
+    let err = Command::new("false").run_async().await.unwrap_err();
+    assert!(err.is_exit_error());
 
-    // Check, exit non-zero
-    cmd.program = Path::new("false").into();
-    assert!(cmd.run().unwrap_err().is_exit_error());
+    let err = Command::with_args("sleep", ["10"])
+        .set_timeout(Duration::from_millis(100))
+        .run_async()
+        .await
+        .unwrap_err();
+    assert!(err.is_timeout_error());
 
-    // No check
-    cmd.check = false;
-    assert!(cmd.run().is_ok());
+    Ok(())
 }
 
 #[test]
-fn test_split_str() {
-    assert!(Command::from_whitespace_separated_str("").is_none());
-    assert!(Command::from_whitespace_separated_str(" ").is_none());
-    assert_eq!(
-        Command::from_whitespace_separated_str("abc"),
-        Some(Command::new("abc"))
+fn test_capture_tagged() -> Result<(), anyhow::Error> {
+    let mut cmd = Command::with_args(
+        "sh",
+        ["-c", "echo 1; sleep 0.1; echo 2 >&2; sleep 0.1; echo 3"],
     );
+    cmd.capture_tagged = true;
+    let output = cmd.enable_capture().run()?;
     assert_eq!(
-        Command::from_whitespace_separated_str("abc 123 456"),
-        Some(Command::with_args("abc", &["123", "456"]))
+        output.tagged_output,
+        [
+            (Stream::Stdout, b"1\n".to_vec()),
+            (Stream::Stderr, b"2\n".to_vec()),
+            (Stream::Stdout, b"3\n".to_vec()),
+        ]
     );
+    assert_eq!(output.stdout, b"1\n3\n");
+    assert_eq!(output.stderr, b"2\n");
+    Ok(())
 }
 
 #[test]
-fn test_args() -> Result<(), anyhow::Error> {
-    let out = Command::with_args("echo", &["hello", "world"])
-        .enable_capture()
-        .run()?;
-    assert_eq!(out.stdout, b"hello world\n");
+fn test_new_process_group() -> Result<(), anyhow::Error> {
+    let tmp_dir = TempDir::new()?;
+    let pid_path = tmp_dir.path().join("pid");
+
+    // Start a shell that runs `sleep` in the background
+    let mut cmd =
+        Command::with_args("sh", ["-c", "sleep 30 & echo $! > pid; wait"]);
+    cmd.set_dir(tmp_dir.path());
+    cmd.new_process_group = true;
+    let mut child = cmd.spawn()?;
+
+    let sleep_pid = loop {
+        if let Ok(pid) = fs::read_to_string(&pid_path) {
+            if pid.ends_with('\n') {
+                break pid.trim().to_string();
+            }
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+
+    child.kill()?;
+    assert!(child.wait().unwrap_err().is_signal_error());
+
+    // The `sleep` should also be gone. It might briefly remain as a
+    // zombie, depending on what reaps orphaned processes.
+    let stat_path = format!("/proc/{}/stat", sleep_pid);
+    for _ in 0..100 {
+        match fs::read_to_string(&stat_path) {
+            Ok(stat) if !stat.contains(") Z ") => {
+                std::thread::sleep(Duration::from_millis(10))
+            }
+            _ => return Ok(()),
+        }
+    }
+    panic!("sleep process still running");
+}
+
+#[test]
+fn test_nice() -> Result<(), anyhow::Error> {
+    // Running `nice` without arguments prints the current nice value
+    let mut cmd = Command::new("nice");
+    cmd.nice = Some(10);
+    let output = cmd.enable_capture().run()?;
+    assert_eq!(output.stdout_string_lossy(), "10\n");
     Ok(())
 }
 
+#[cfg(unix)]
 #[test]
-fn test_add_arg_variations() {
-    let mut cmd = Command::new("a");
-    cmd.add_arg("b");
-    cmd.add_arg_pair("c", Path::new("d"));
-    cmd.add_args(&["e", "f", "g"]);
-    assert_eq!(cmd.command_line_lossy(), "a b c d e f g");
+fn test_uid_gid() -> Result<(), anyhow::Error> {
+    let is_root = Command::with_args("id", ["-u"])
+        .capture()
+        .run()?
+        .stdout_string_lossy()
+        == "0\n";
+
+    let mut cmd = Command::with_args("id", ["-u"]);
+    cmd.uid = Some(65534);
+    cmd.gid = Some(65534);
+    cmd.enable_capture();
+    if is_root {
+        assert_eq!(cmd.run()?.stdout_string_lossy(), "65534\n");
+    } else {
+        let err = cmd.run().unwrap_err();
+        assert!(err.is_run_error());
+    }
+    Ok(())
 }
 
 #[test]
-fn test_command_line() {
-    assert_eq!(Command::new("test").command_line_lossy(), "test");
-    assert_eq!(
-        Command::with_args("test", &["hello", "world"]).command_line_lossy(),
-        "test hello world"
-    );
+fn test_before_run() -> Result<(), anyhow::Error> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
-    assert_eq!(
-        Command::with_args("a b", &["c d", "e"]).command_line_lossy(),
-        "'a b' 'c d' e"
-    );
+    let count = Arc::new(AtomicUsize::new(0));
+    let count_clone = count.clone();
+    let mut cmd = Command::new("true");
+    cmd.before_run = Some(Arc::new(move |cmd| {
+        assert_eq!(cmd.program, Path::new("true"));
+        count_clone.fetch_add(1, Ordering::SeqCst);
+    }));
+
+    cmd.run()?;
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+    cmd.run()?;
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+
+    Ok(())
+}
+
+#[test]
+fn test_after_run() {
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let results_clone = results.clone();
+    let after_run: command_run::ResultCallback =
+        Arc::new(move |cmd, result| {
+            results_clone
+                .lock()
+                .unwrap()
+                .push((cmd.command_line_lossy(), result.is_ok()))
+        });
+
+    let mut cmd = Command::new("true");
+    cmd.after_run = Some(after_run.clone());
+    assert!(cmd.run().is_ok());
+
+    let mut cmd = Command::new("false");
+    cmd.after_run = Some(after_run);
+    assert!(cmd.run().is_err());
 
-    // Check that some special characters do not cause quoting
     assert_eq!(
-        Command::with_args("a", &["-_/,:.=+"]).command_line_lossy(),
-        "a -_/,:.=+"
+        *results.lock().unwrap(),
+        [("true".to_string(), true), ("false".to_string(), false)]
     );
 }
 
@@ -142,7 +1829,7 @@ impl TestProg {
         let prog_path = tmpdir.path().join("testprog");
         Command::new("rustc")
             .add_arg("-o")
-            .add_args(&[&prog_path, &code_path])
+            .add_args([&prog_path, &code_path])
             .run()?;
 
         Ok(TestProg {
@@ -172,6 +1859,405 @@ fn test_combine_output() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+#[test]
+fn test_tee_output() -> Result<(), anyhow::Error> {
+    // Teeing the output does not change what is captured
+    let mut testprog = TestProg::new()?;
+    testprog.command.capture = true;
+    testprog.command.combine_output = true;
+    testprog.command.tee_output = true;
+    testprog.command.check = false;
+
+    let output = testprog.command.run()?;
+    assert_eq!(output.stdout_string_lossy(), "test-stdout\ntest-stderr\n");
+    assert!(output.stderr.is_empty());
+
+    testprog.command.combine_output = false;
+    let output = testprog.command.run()?;
+    assert_eq!(output.stdout_string_lossy(), "test-stdout\n");
+    assert_eq!(output.stderr_string_lossy(), "test-stderr\n");
+    Ok(())
+}
+
+#[test]
+fn test_tail_output_bytes() -> Result<(), anyhow::Error> {
+    // Print the numbers 1 through 10000, one per line.
+    let mut cmd = Command::with_args("seq", ["10000"]);
+    cmd.enable_capture();
+    cmd.tail_output_bytes = Some(10);
+    let output = cmd.run()?;
+    assert_eq!(output.stdout_string_lossy(), "999\n10000\n");
+
+    cmd.combine_output = true;
+    let output = cmd.run()?;
+    assert_eq!(output.stdout.len(), 10);
+    Ok(())
+}
+
+#[test]
+fn test_max_output_lines() -> Result<(), anyhow::Error> {
+    // Unbounded output is stopped.
+    let mut cmd = Command::new("yes");
+    cmd.capture = true;
+    cmd.max_output_lines = Some(100);
+    let err = cmd.run().unwrap_err();
+    assert!(err.is_output_limit_error());
+    assert!(matches!(err.kind, ErrorKind::OutputLimit(100)));
+    assert!(err.output.unwrap().stdout_lines().count() > 100);
+
+    // The limit also applies if the command exits first.
+    let mut cmd = Command::with_args("seq", ["101"]);
+    cmd.capture = true;
+    cmd.max_output_lines = Some(100);
+    assert!(cmd.run().unwrap_err().is_output_limit_error());
+
+    // Lines from both streams are counted.
+    cmd.args = vec!["100".into()];
+    assert!(cmd.run().is_ok());
+    let mut cmd = Command::with_args("sh", ["-c", "seq 50; seq 51 >&2"]);
+    cmd.capture = true;
+    cmd.max_output_lines = Some(100);
+    assert!(cmd.run().unwrap_err().is_output_limit_error());
+
+    Ok(())
+}
+
+#[test]
+fn test_stream_config() -> Result<(), anyhow::Error> {
+    use command_run::StreamConfig;
+
+    let tmp_dir = TempDir::new()?;
+    let path = tmp_dir.path().join("out");
+
+    let mut testprog = TestProg::new()?;
+    testprog.command.check = false;
+    let mut run = |stdout, stderr| {
+        testprog.command.stdout_config = Some(stdout);
+        testprog.command.stderr_config = Some(stderr);
+        testprog.command.run().unwrap()
+    };
+
+    let output = run(StreamConfig::Capture, StreamConfig::Null);
+    assert_eq!(output.stdout_string_lossy(), "test-stdout\n");
+    assert!(output.stderr.is_empty());
+
+    let output = run(StreamConfig::Null, StreamConfig::Capture);
+    assert!(output.stdout.is_empty());
+    assert_eq!(output.stderr_string_lossy(), "test-stderr\n");
+
+    let output = run(StreamConfig::File(path.clone()), StreamConfig::Capture);
+    assert!(output.stdout.is_empty());
+    assert_eq!(output.stderr_string_lossy(), "test-stderr\n");
+    assert_eq!(fs::read_to_string(&path)?, "test-stdout\n");
+
+    let output = run(StreamConfig::Inherit, StreamConfig::Null);
+    assert!(output.stdout.is_empty());
+    assert!(output.stderr.is_empty());
+
+    // Stderr can be sent to stdout
+    let output = run(StreamConfig::Capture, StreamConfig::Stdout);
+    assert_eq!(output.stdout_string_lossy(), "test-stdout\ntest-stderr\n");
+    assert!(output.stderr.is_empty());
+
+    let output = run(StreamConfig::File(path.clone()), StreamConfig::Stdout);
+    assert!(output.stdout.is_empty());
+    assert_eq!(fs::read_to_string(&path)?, "test-stdout\ntest-stderr\n");
+
+    let output = run(StreamConfig::Null, StreamConfig::Stdout);
+    assert!(output.stdout.is_empty());
+
+    // The configs take precedence over `capture`
+    testprog.command.capture = true;
+    testprog.command.stdout_config = Some(StreamConfig::Null);
+    testprog.command.stderr_config = None;
+    let output = testprog.command.run()?;
+    assert!(output.stdout.is_empty());
+    assert_eq!(output.stderr_string_lossy(), "test-stderr\n");
+
+    // Output can be combined without setting `capture`
+    testprog.command.capture = false;
+    testprog.command.combine_output = true;
+    testprog.command.stdout_config = Some(StreamConfig::Capture);
+    testprog.command.stderr_config = Some(StreamConfig::Capture);
+    let output = testprog.command.run()?;
+    assert_eq!(output.stdout_string_lossy(), "test-stdout\ntest-stderr\n");
+    assert!(output.stderr.is_empty());
+
+    // Stdout cannot be sent to stdout
+    testprog.command.stdout_config = Some(StreamConfig::Stdout);
+    assert!(testprog.command.run().unwrap_err().is_run_error());
+
+    Ok(())
+}
+
+#[test]
+fn test_stdin_config() -> Result<(), anyhow::Error> {
+    use command_run::StreamConfig;
+
+    let tmp_dir = TempDir::new()?;
+    let path = tmp_dir.path().join("in");
+    fs::write(&path, "hello")?;
+
+    // Takes precedence over `stdin`
+    let mut cmd = Command::new("cat");
+    cmd.capture = true;
+    cmd.stdin = StdinSource::Bytes(b"bytes".to_vec());
+    cmd.stdin_config = Some(StreamConfig::File(path));
+    assert_eq!(cmd.run()?.stdout, b"hello");
+
+    cmd.stdin_config = Some(StreamConfig::Null);
+    assert!(cmd.run()?.stdout.is_empty());
+
+    for config in [StreamConfig::Capture, StreamConfig::Stdout] {
+        cmd.stdin_config = Some(config);
+        assert!(cmd.run().unwrap_err().is_run_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, feature = "pty"))]
+#[test]
+fn test_use_pty() -> Result<(), anyhow::Error> {
+    let mut cmd = Command::with_args(
+        "sh",
+        [
+            "-c",
+            "test -t 1 && echo stdout-tty; test -t 2 && echo stderr-tty",
+        ],
+    );
+    cmd.check = false;
+    cmd.enable_capture();
+    assert_eq!(cmd.run()?.stdout_string_lossy(), "");
+
+    cmd.use_pty = true;
+    let output = cmd.run()?;
+    assert_eq!(output.stdout_string_lossy(), "stdout-tty\r\nstderr-tty\r\n");
+    assert!(output.stderr.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_stderr_to_parent() -> Result<(), anyhow::Error> {
+    let mut testprog = TestProg::new()?;
+    testprog.command.capture = true;
+    testprog.command.stderr_to_parent = true;
+    testprog.command.check = false;
+
+    let output = testprog.command.run().unwrap();
+    assert_eq!(output.stdout_string_lossy(), "test-stdout\n");
+    assert!(output.stderr.is_empty());
+
+    // Not allowed with combine_output
+    testprog.command.combine_output = true;
+    let err = testprog.command.run().unwrap_err();
+    assert!(err.is_run_error());
+
+    Ok(())
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_log_to_tracing() -> Result<(), anyhow::Error> {
+    use command_run::LogTo;
+    use std::io::{self, Write};
+
+    struct BufWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for BufWriter {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buf = Arc::new(Mutex::new(Vec::new()));
+    let writer_buf = buf.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .without_time()
+        .with_writer(move || BufWriter(writer_buf.clone()))
+        .finish();
+
+    let mut cmd = Command::with_args("echo", ["hello"]);
+    cmd.capture = true;
+    cmd.log_output_always = true;
+    cmd.log_to = LogTo::Tracing;
+    let succeeded =
+        tracing::subscriber::with_default(subscriber, || cmd.run().is_ok());
+    assert!(succeeded);
+
+    let log = String::from_utf8(buf.lock().unwrap().clone())?;
+    let lines: Vec<_> = log.lines().collect();
+    assert_eq!(
+        lines[0],
+        " INFO command{command=echo hello}: command_run: echo hello"
+    );
+    assert_eq!(
+        lines[1],
+        " INFO command{command=echo hello}: command_run: \
+         command 'echo hello' finished: exit status: 0"
+    );
+    assert_eq!(lines[3], "hello");
+    Ok(())
+}
+
+/// Check that `line` starts with an RFC 3339 timestamp followed by a
+/// space, and return the rest of the line.
+#[cfg(feature = "time")]
+fn strip_timestamp(line: &str) -> &str {
+    // For example: 2024-01-02T03:04:05.123456789Z
+    let (timestamp, rest) = line.split_once(' ').unwrap();
+    let bytes = timestamp.as_bytes();
+    assert!(timestamp.len() >= 20, "{timestamp}");
+    assert_eq!(
+        (bytes[4], bytes[7], bytes[10], bytes[13], bytes[16]),
+        (b'-', b'-', b'T', b':', b':')
+    );
+    assert!(timestamp.ends_with('Z'));
+    rest
+}
+
+#[cfg(feature = "time")]
+#[test]
+fn test_log_timestamps() -> Result<(), anyhow::Error> {
+    use command_run::LogTo;
+
+    let buf = Arc::new(Mutex::new(Vec::new()));
+    let mut cmd = Command::with_args("echo", ["hello"]);
+    cmd.capture = true;
+    cmd.log_output_always = true;
+    cmd.log_to = LogTo::Writer(buf.clone());
+    cmd.log_timestamps = true;
+    cmd.run()?;
+
+    let log = String::from_utf8(buf.lock().unwrap().clone())?;
+    let lines: Vec<_> = log.lines().map(strip_timestamp).collect();
+    assert_eq!(lines.len(), 7);
+    assert_eq!(lines[0], "echo hello");
+    assert_eq!(lines[3], "hello");
+    Ok(())
+}
+
+#[cfg(feature = "time")]
+#[test]
+fn test_log_timestamps_tee() -> Result<(), anyhow::Error> {
+    const ENV_VAR: &str = "COMMAND_RUN_TEST_LOG_TIMESTAMPS_TEE";
+
+    // Output copied by `tee_output` goes to the stdout of this
+    // process, so this test runs itself in a child process to check
+    // it.
+    if std::env::var_os(ENV_VAR).is_some() {
+        let mut cmd = Command::with_args("printf", ["a\\nb\\n"]);
+        cmd.capture = true;
+        cmd.tee_output = true;
+        cmd.log_command = false;
+        cmd.log_timestamps = true;
+        let output = cmd.run()?;
+        // The captured output is not changed
+        assert_eq!(output.stdout_string_lossy(), "a\nb\n");
+        return Ok(());
+    }
+
+    let output = Command::with_args(
+        std::env::current_exe()?,
+        ["--exact", "test_log_timestamps_tee", "--nocapture"],
+    )
+    .env(ENV_VAR, "1")
+    .capture()
+    .run()?;
+    let stdout = output.stdout_string_lossy();
+    let lines: Vec<_> = stdout
+        .lines()
+        .filter(|line| line.ends_with(" a") || line.ends_with(" b"))
+        // The test harness may write the test name on the same line
+        .map(|line| line.rsplit_once(" ... ").map_or(line, |(_, rest)| rest))
+        .map(strip_timestamp)
+        .collect();
+    assert_eq!(lines, ["a", "b"]);
+    Ok(())
+}
+
+#[test]
+fn test_log_to_writer() -> Result<(), anyhow::Error> {
+    use command_run::LogTo;
+
+    let buf = Arc::new(Mutex::new(Vec::new()));
+    let mut cmd = Command::with_args("false", ["a b"]);
+    cmd.capture = true;
+    cmd.log_output_on_error = true;
+    cmd.log_to = LogTo::Writer(buf.clone());
+    assert!(cmd.run().unwrap_err().is_exit_error());
+
+    assert_eq!(
+        String::from_utf8(buf.lock().unwrap().clone())?,
+        "false 'a b'
+command 'false 'a b'' failed: exit status: 1
+stdout:
+
+stderr:
+
+"
+    );
+
+    // Writers are compared by pointer
+    assert_eq!(cmd.clone().log_to, cmd.log_to);
+    assert_ne!(
+        cmd.log_to,
+        LogTo::Writer(Arc::new(Mutex::new(Vec::<u8>::new())))
+    );
+    Ok(())
+}
+
+#[test]
+fn test_heartbeat_interval() -> Result<(), anyhow::Error> {
+    use command_run::LogTo;
+
+    let buf = Arc::new(Mutex::new(Vec::new()));
+    let mut cmd = Command::with_args("sleep", ["1"]);
+    cmd.log_command = false;
+    cmd.log_to = LogTo::Writer(buf.clone());
+    cmd.heartbeat_interval = Some(Duration::from_millis(200));
+    cmd.run()?;
+
+    let log = String::from_utf8(buf.lock().unwrap().clone())?;
+    assert!(log.lines().count() >= 1);
+    for line in log.lines() {
+        assert!(line.starts_with("still running after "));
+        assert!(line.ends_with("s: sleep 1"));
+    }
+
+    // A zero interval does not log anything
+    buf.lock().unwrap().clear();
+    cmd.heartbeat_interval = Some(Duration::ZERO);
+    cmd.args = vec!["0.1".into()];
+    cmd.run()?;
+    assert!(buf.lock().unwrap().is_empty());
+    Ok(())
+}
+
+#[cfg(feature = "logging")]
+#[test]
+fn test_quiet() -> Result<(), anyhow::Error> {
+    use command_run::LogTo;
+
+    capture_logger::init();
+
+    let mut cmd = Command::new("false");
+    cmd.capture = true;
+    cmd.log_output_on_error = true;
+    cmd.log_to = LogTo::Log;
+    cmd.quiet();
+    assert!(!cmd.log_command);
+    assert!(!cmd.log_output_on_error);
+
+    assert!(cmd.run().unwrap_err().is_exit_error());
+    assert!(capture_logger::get_logs().is_empty());
+    Ok(())
+}
+
 #[cfg(feature = "logging")]
 #[test]
 fn test_log() -> Result<(), anyhow::Error> {
@@ -232,3 +2318,77 @@ test-stderr
 
     Ok(())
 }
+
+#[cfg(feature = "logging")]
+#[test]
+fn test_log_output_always() -> Result<(), anyhow::Error> {
+    use command_run::LogTo;
+    use log::Level;
+
+    capture_logger::init();
+
+    let mut cmd = Command::with_args("echo", ["hi"]);
+    cmd.capture = true;
+    cmd.log_command = false;
+    cmd.log_output_always = true;
+    cmd.log_to = LogTo::Log;
+    cmd.run()?;
+
+    assert_eq!(
+        capture_logger::get_logs(),
+        vec![(
+            Level::Info,
+            "command 'echo hi' finished: exit status: 0
+stdout:
+hi
+
+stderr:
+"
+            .to_string()
+        )]
+    );
+    Ok(())
+}
+
+#[cfg(feature = "logging")]
+#[test]
+fn test_log_level() -> Result<(), anyhow::Error> {
+    use command_run::LogTo;
+    use log::Level;
+
+    capture_logger::init();
+
+    let mut cmd = Command::new("true");
+    cmd.log_to = LogTo::Log;
+    cmd.log_level = Level::Debug;
+    cmd.run()?;
+    assert_eq!(
+        capture_logger::get_logs(),
+        vec![(Level::Debug, "true".to_string())]
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "structured_logging")]
+#[test]
+fn test_structured_logging() -> Result<(), anyhow::Error> {
+    use command_run::LogTo;
+
+    capture_logger::init();
+
+    let mut cmd = Command::with_args("true", ["a", "b"]);
+    cmd.log_to = LogTo::Log;
+    cmd.set_dir("/");
+    cmd.run()?;
+    assert_eq!(
+        capture_logger::get_key_values(),
+        vec![vec![
+            ("program".to_string(), "true".to_string()),
+            ("args".to_string(), "2".to_string()),
+            ("dir".to_string(), "/".to_string()),
+        ]]
+    );
+
+    Ok(())
+}
@@ -1,3 +1,6 @@
+// A large error type is not a problem in a test.
+#![allow(clippy::result_large_err)]
+
 use command_run::{Command, Error};
 
 #[test]
@@ -5,7 +8,7 @@ fn test_example() -> Result<(), Error> {
     // Begin readme example
     // This will return an error if the command did not exit successfully
     // (controlled with the `check` field).
-    let output = Command::with_args("echo", &["hello", "world"])
+    let output = Command::with_args("echo", ["hello", "world"])
         .enable_capture()
         .run()?;
     assert_eq!(output.stdout_string_lossy(), "hello world\n");